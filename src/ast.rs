@@ -1,29 +1,141 @@
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::Signed;
+
+use std::collections::HashMap;
+use std::fmt;
+
+/// Where a node came from in the source
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Span {
+    /// Byte offset of the first byte
+    pub start: usize,
+    /// Byte offset past the last byte
+    pub end: usize,
+    /// 1-based line of `start`
+    pub line: usize,
+    /// 1-based column of `start`, in chars
+    pub column: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize, source: &str) -> Self {
+        let before = &source[..start];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        Span {
+            start,
+            end,
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+        }
+    }
+
+    /// The line the span starts on, with the span underlined by carets
+    pub fn excerpt(&self, source: &str) -> String {
+        let line_start = source[..self.start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[self.start..].find('\n').map_or(source.len(), |i| self.start + i);
+        let line = &source[line_start..line_end];
+        let end = if self.end > line_end { line_end } else { self.end };
+        let carets = source[self.start..end].chars().count();
+        let gutter = " ".repeat(self.line.to_string().len());
+
+        format!(
+            "{} |\n{} | {}\n{} | {}{}",
+            gutter,
+            self.line, line,
+            gutter, " ".repeat(self.column.saturating_sub(1)), "^".repeat(if carets == 0 { 1 } else { carets })
+        )
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Expression {
-    Composition(Vec<Expression>),      // `Comp`? Ugly. `Cmpstn`? Ugly as C. `Compose`? Meh.
-    Concatenation(Vec<Expression>),    // no escape from long long names
-    Question(Box<Expression>, Box<Expression>),
-    Word(Word),
-    Integer(i64),
-    Float(f64),
-    String(String),
-    Quotation(Box<Expression>),
-    Nop,    // all right, doing nothing is important
+    Composition(Vec<Expression>, Span),      // `Comp`? Ugly. `Cmpstn`? Ugly as C. `Compose`? Meh.
+    Concatenation(Vec<Expression>, Span),    // no escape from long long names
+    Question(Box<Expression>, Box<Expression>, Span),
+    Word(Word, Span),
+    Integer(BigInt, Span),
+    Rational(BigRational, Span),
+    Float(f64, Span),
+    String(String, Span),
+    Quotation(Box<Expression>, Span),
+    Nop(Span),    // all right, doing nothing is important
     /// `` foo `bar` ``
-    InfixLeft(Box<Expression>, Box<Expression>),
+    InfixLeft(Box<Expression>, Box<Expression>, Span),
     /// `` `foo` bar ``
-    InfixRight(Box<Expression>, Box<Expression>)
+    InfixRight(Box<Expression>, Box<Expression>, Span),
+    /// A defined word, by its name
+    Call(String, Span),
+    /// `square ← ⇈ × ⋄ 3 square`: definitions, and the program that can call them
+    Definitions(Vec<Definition<Expression>>, Box<Expression>, Span),
+}
+
+/// `name ⇐ 'path' ⋄`: the definitions of another file, called as `name::word`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Import {
+    pub name: String,
+    /// Relative to the importing file
+    pub path: String,
+    pub span: Span,
+}
+
+/// `name ← body ⋄`, or `name 1 → 1 ← body ⋄` with a declared arity
+#[derive(Debug, Clone, PartialEq)]
+pub struct Definition<E> {
+    pub name: String,
+    /// Needed for calls made before the body is done, like the ones a word makes to itself
+    pub declared: Option<Arity>,
+    pub body: E,
+    pub span: Span,
+}
+
+impl Expression {
+    pub fn span(&self) -> Span {
+        use self::Expression::*;
+        match *self {
+            Composition(_, span)
+            | Concatenation(_, span)
+            | Question(_, _, span)
+            | Word(_, span)
+            | Integer(_, span)
+            | Rational(_, span)
+            | Float(_, span)
+            | String(_, span)
+            | Quotation(_, span)
+            | Nop(span)
+            | InfixLeft(_, _, span)
+            | InfixRight(_, _, span)
+            | Call(_, span)
+            | Definitions(_, _, span) => span,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Word {
     Gt,
     Eq,
+    NotEq,
     Lt,
+    True,
+    False,
+    And,
+    Or,
+    Xor,
+    Not,
     Plus,
     Minus,
     Prod,
     Div,
+    Rem,
+    Pow,
+    Floor,
+    Ceil,
+    Round,
+    Trunc,
+    ToFloat,
+    Negate,
+    Abs,
     Swap,
     Dup,
     Drop,
@@ -31,186 +143,931 @@ pub enum Word {
     Zilde,
     Comma,
     Behead,
+    /// The last element, and the list without it
+    Curtail,
+    Length,
+    Index,
+    Catenate,
+    Reverse,
+    /// The first `n` elements of a list
+    Take,
+    /// All but the first `n` elements of a list
+    Skip,
+    Member,
     Print,
+    Show,
+    ReadLine,
+    ReadAll,
+    ReadNumber,
     Rec,
+    Apply,
+    Times,
+    While,
+}
+
+impl Word {
+    /// The glyph this word is written with
+    pub fn symbol(self) -> &'static str {
+        use self::Word::*;
+        match self {
+            Gt => ">",
+            Eq => "=",
+            NotEq => "≠",
+            Lt => "<",
+            True => "⊤",
+            False => "⊥",
+            And => "∧",
+            Or => "∨",
+            Xor => "⊻",
+            Not => "~",
+            Plus => "+",
+            Minus => "−",
+            Prod => "×",
+            Div => "÷",
+            Rem => "|",
+            Pow => "*",
+            Floor => "⌊",
+            Ceil => "⌈",
+            Round => "⊚",
+            Trunc => "⊘",
+            ToFloat => "⊙",
+            Negate => "±",
+            Abs => "‖",
+            Swap => "↔",
+            Dup => "⇈",
+            Drop => "↓",
+            Id => "·",
+            Zilde => "⍬",
+            Comma => ",",
+            Behead => "⍘",
+            Curtail => "⍙",
+            Length => "≢",
+            Index => "⌷",
+            Catenate => "⍪",
+            Reverse => "⌽",
+            Take => "↑",
+            Skip => "⇣",
+            Member => "∊",
+            Print => "⎕",
+            Show => "⍕",
+            ReadLine => "⍞",
+            ReadAll => "⍇",
+            ReadNumber => "⍈",
+            Rec => "∇",
+            Apply => "⍎",
+            Times => "⍣",
+            While => "⍤",
+        }
+    }
 }
 
 fn word_arity(w: &Word) -> Arity {
     use self::Word::*;
     match *w {
-        Gt | Eq | Lt
+        Gt | Eq | NotEq | Lt
         | Plus | Minus
-        | Prod | Div => Arity(2, 1),
+        | Prod | Div
+        | Rem | Pow
+        | And | Or | Xor => Arity(2, 1),
+        True | False => Arity(0, 1),
+        Floor | Ceil | Round | Trunc
+        | ToFloat | Negate | Abs
+        | Not | Show
+        | Length | Reverse => Arity(1, 1),
         Swap => Arity(2, 2),
         Dup => Arity(1, 2),
         Drop => Arity(1, 0),
         Id => Arity(1, 1),
         Zilde => Arity(0, 1),
         Comma => Arity(2, 1),
-        Behead => Arity(1, 2),
+        Behead | Curtail => Arity(1, 2),
+        Index | Catenate | Take | Skip | Member => Arity(2, 1),
         Print => Arity(1, 0),
-        Rec => Arity(2, 1)
+        ReadLine => Arity(0, 2),
+        ReadAll => Arity(0, 1),
+        ReadNumber => Arity(0, 2),
+        // For as many values as the quotation carries through, but these just come out as they went in.
+        // Where the quotation is not known, it has to carry one through `∇` and none through the others
+        Rec => Arity(2, 1),
+        Times | While => Arity(2, 0),
+        // Only the quotation itself. What the quotation takes is only known when it runs
+        Apply => Arity(1, 0),
     }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Arity(pub u32, pub u32);
 
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} → {}", self.0, self.1)
+    }
+}
+
 impl Arity {
     fn concat(self, other: Arity) -> Self {
-        Arity(self.0 + other.0, self.1 + other.1)
+        Arity(self.0.saturating_add(other.0), self.1.saturating_add(other.1))
     }
 
     fn compose(self, other: Arity) -> Self {
-        let ar_in = self.0 + if other.0 > self.1 { other.0 - self.1 } else { 0 };
-        let ar_out = other.1 + if self.1 > other.0 { self.1 - other.0 } else { 0 };
+        let ar_in = self.0.saturating_add(other.0.saturating_sub(self.1));
+        let ar_out = other.1.saturating_add(self.1.saturating_sub(other.0));
         Arity(ar_in, ar_out)
     }
 }
 
+/// The branches of a `?...:...` question take or leave different numbers of values
+#[derive(Debug, Clone, PartialEq)]
+pub struct BranchMismatch {
+    pub question: Span,
+    pub cons: (Arity, Span),
+    pub alter: (Arity, Span),
+}
+
+/// A quotation handed to a combinator that cannot run it
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuotationMismatch {
+    pub combinator: (Word, Span),
+    pub quotation: (Arity, Span),
+    /// The shape of arity the combinator wants, like `n → n+1`
+    pub wanted: &'static str,
+}
+
+/// A defined word that is called or defined in a way the arity pass cannot follow
+#[derive(Debug, Clone, PartialEq)]
+pub enum DefinitionError {
+    /// Called, but defined nowhere
+    Undefined(String, Span),
+    /// Called before its definition is done, with no arity declared
+    Undeclared(String, Span),
+    /// Declared with one arity, but with a body of another
+    Declared { name: String, declared: Arity, body: Arity, span: Span },
+    /// Defined again
+    Duplicate(String, Span),
+}
+
+impl DefinitionError {
+    /// Where the call or the definition is
+    pub fn span(&self) -> Span {
+        match *self {
+            DefinitionError::Undefined(_, span)
+            | DefinitionError::Undeclared(_, span)
+            | DefinitionError::Declared { span, .. }
+            | DefinitionError::Duplicate(_, span) => span,
+        }
+    }
+}
+
+/// Every mismatch found in a program
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArityError {
+    pub mismatches: Vec<BranchMismatch>,
+    pub quotations: Vec<QuotationMismatch>,
+    pub definitions: Vec<DefinitionError>,
+}
+
+impl fmt::Display for BranchMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "branches of `?` at {}:{} disagree: {} at {}:{}, but {} at {}:{}",
+            self.question.line, self.question.column,
+            self.cons.0, self.cons.1.line, self.cons.1.column,
+            self.alter.0, self.alter.1.line, self.alter.1.column
+        )
+    }
+}
+
+impl fmt::Display for QuotationMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "quotation at {}:{} is {}, but `{}` at {}:{} wants {}",
+            self.quotation.1.line, self.quotation.1.column, self.quotation.0,
+            self.combinator.0.symbol(), self.combinator.1.line, self.combinator.1.column,
+            self.wanted
+        )
+    }
+}
+
+impl fmt::Display for DefinitionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let span = self.span();
+        match *self {
+            DefinitionError::Undefined(ref name, _) => {
+                write!(f, "`{}` at {}:{} is not defined", name, span.line, span.column)
+            },
+            DefinitionError::Undeclared(ref name, _) => write!(
+                f,
+                "`{}` at {}:{} is called before it is defined, so it needs a declared arity, like `{} 1 → 1 ←`",
+                name, span.line, span.column, name
+            ),
+            DefinitionError::Declared { ref name, declared, body, .. } => write!(
+                f,
+                "`{}` at {}:{} is declared {}, but its body is {}",
+                name, span.line, span.column, declared, body
+            ),
+            DefinitionError::Duplicate(ref name, _) => {
+                write!(f, "`{}` at {}:{} is already defined", name, span.line, span.column)
+            },
+        }
+    }
+}
+
+impl fmt::Display for ArityError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let branches = self.mismatches.iter().map(|m| m.to_string());
+        let quotations = self.quotations.iter().map(|m| m.to_string());
+        let definitions = self.definitions.iter().map(|m| m.to_string());
+        for (i, m) in branches.chain(quotations).chain(definitions).enumerate() {
+            if i > 0 { writeln!(f)? }
+            write!(f, "{}", m)?;
+        }
+        Ok(())
+    }
+}
+
+/// Defined words, and what is known about them once their bodies are done
+type Words = HashMap<String, Option<Defined>>;
+
+/// What a call to a defined word takes and leaves, with the quotations among what it leaves
+#[derive(Debug, Clone)]
+struct Defined {
+    arity: Arity,
+    /// As many as `arity` leaves, the topmost last
+    leaves: Vec<Option<Quoted>>,
+}
+
+impl Defined {
+    /// Nothing known but the arity
+    fn unknown(arity: Arity) -> Self {
+        Defined { arity, leaves: vec![None; arity.1 as usize] }
+    }
+
+    /// What `body` was inferred to be, with `known` what it pushed
+    fn body(body: &Arited, known: &[Option<Quoted>]) -> Self {
+        let arity = body.arity();
+        let n = arity.1 as usize;
+        let pushed = &known[known.len().saturating_sub(n)..];
+        let mut leaves = vec![None; n - pushed.len()];
+        leaves.extend_from_slice(pushed);
+        Defined { arity, leaves }
+    }
+}
+
+/// What the arity pass knows about a value: if it is a quotation, how many values the quotation takes and leaves
+#[derive(Debug, Clone, Copy)]
+struct Quoted {
+    arity: Arity,
+    span: Span,
+}
+
+/// `n` values down from the top of what is known, not counting the top
+fn nth_known(known: &[Option<Quoted>], n: usize) -> Option<Quoted> {
+    known.iter().rev().nth(n).cloned().unwrap_or(None)
+}
+
+/// The values `arity` takes are gone, and nothing is known about the ones it leaves
+fn pass(known: &mut Vec<Option<Quoted>>, arity: Arity) {
+    let kept = known.len().saturating_sub(arity.0 as usize);
+    known.truncate(kept);
+    known.extend((0..arity.1).map(|_| None));
+}
+
+/// What `w` leaves on top of `known`: quotations survive being shuffled around
+fn shuffle(w: Word, arity: Arity, known: &mut Vec<Option<Quoted>>) {
+    let left = match w {
+        Word::Dup => vec![nth_known(known, 0), nth_known(known, 0)],
+        Word::Swap => vec![nth_known(known, 0), nth_known(known, 1)],
+        Word::Id => vec![nth_known(known, 0)],
+        _ => vec![None; arity.1 as usize],
+    };
+    pass(known, Arity(arity.0, 0));
+    known.extend(left);
+}
+
+fn qualified(prefix: Option<&str>, name: String) -> String {
+    match prefix {
+        Some(prefix) => format!("{}::{}", prefix, name),
+        None => name,
+    }
+}
+
+/// Integers the way the parser reads them, with a high minus
+pub(crate) fn integer_literal(i: &BigInt) -> String {
+    if i.is_negative() { format!("¯{}", -i) } else { i.to_string() }
+}
+
+pub(crate) fn rational_literal(r: &BigRational) -> String {
+    format!("{}r{}", integer_literal(r.numer()), r.denom())
+}
+
+/// Floats always with a dot or an exponent, so that they stay floats when read back
+///
+/// There are no literals for infinities and NaN, so they are the divisions that make them.
+pub(crate) fn float_literal(f: f64) -> String {
+    if f.is_nan() {
+        "(0.0 0.0 ÷)".to_string()
+    } else if f.is_infinite() {
+        format!("({}1.0 0.0 ÷)", if f < 0.0 { "¯" } else { "" })
+    } else {
+        format!("{:?}", f).replace('-', "¯")
+    }
+}
+
+/// Strings quoted, with everything that would not read back escaped
+pub(crate) fn string_literal(s: &str) -> String {
+    let mut literal = String::from("'");
+    for c in s.chars() {
+        match c {
+            '\n' => literal.push_str("\\n"),
+            '\t' => literal.push_str("\\t"),
+            '\r' => literal.push_str("\\r"),
+            '\0' => literal.push_str("\\0"),
+            '\'' => literal.push_str("\\'"),
+            '\\' => literal.push_str("\\\\"),
+            c if c.is_control() => literal.push_str(&format!("\\u{{{:X}}}", c as u32)),
+            c => literal.push(c),
+        }
+    }
+    literal.push('\'');
+    literal
+}
+
 // Feels like unnecessary boilerplate. But whatever.
 #[derive(Debug, Clone, PartialEq)]
 pub enum Arited {
-    Composition(Vec<Arited>, Arity),
-    Concatenation(Vec<Arited>, Arity),
-    Question(Box<Arited>, Box<Arited>, Arity),
-    Word(Word, Arity),
-    Integer(i64),
-    Float(f64),
-    String(String),
-    Quotation(Box<Arited>),
+    Composition(Vec<Arited>, Arity, Span),
+    Concatenation(Vec<Arited>, Arity, Span),
+    Question(Box<Arited>, Box<Arited>, Arity, Span),
+    Word(Word, Arity, Span),
+    Integer(BigInt, Span),
+    Rational(BigRational, Span),
+    Float(f64, Span),
+    String(String, Span),
+    Quotation(Box<Arited>, Span),
     /// `a1 a2 ... an -> a1 a2 ... an`
-    IdN(u32),
+    IdN(u32, Span),
+    Call(String, Arity, Span),
+    Definitions(Vec<Definition<Arited>>, Box<Arited>, Span),
+}
+
+impl Definition<Arited> {
+    /// The definition as an importing module sees it: under `prefix::`, if there is one, with
+    /// everything it calls too
+    ///
+    /// Its spans point into another file, so all of them become `span`, the import it came through.
+    pub fn imported(self, prefix: Option<&str>, span: Span) -> Self {
+        self.rewritten(&|name| qualified(prefix, name), Some(span))
+    }
+
+    /// The definition under the name `rename` gives it, with everything it calls renamed the same way
+    pub fn renamed(self, rename: &dyn Fn(String) -> String) -> Self {
+        self.rewritten(rename, None)
+    }
+
+    fn rewritten(self, rename: &dyn Fn(String) -> String, span: Option<Span>) -> Self {
+        Definition {
+            name: rename(self.name),
+            declared: self.declared,
+            body: self.body.rewritten(rename, span),
+            span: span.unwrap_or(self.span),
+        }
+    }
 }
 
 impl Arited {
-    pub fn from_expression(e: Expression) -> Self {
+    pub fn from_expression(e: Expression) -> Result<Self, ArityError> {
+        Arited::from_expression_in(e, &[])
+    }
+
+    /// Like `from_expression`, for code that can also call `imported` words
+    pub fn from_expression_in(e: Expression, imported: &[Definition<Arited>]) -> Result<Self, ArityError> {
+        let mut errors = ArityError { mismatches: vec![], quotations: vec![], definitions: vec![] };
+        // Imported words leave the same quotations as they did where they were defined
+        let mut words = Words::new();
+        for d in imported {
+            let defined = match d.declared {
+                Some(declared) => Defined::unknown(declared),
+                None => {
+                    let mut pushed = vec![];
+                    d.body.leaves(&words, &mut pushed);
+                    Defined::body(&d.body, &pushed)
+                },
+            };
+            words.insert(d.name.clone(), Some(defined));
+        }
+        let arited = Arited::infer(e, &words, &mut vec![], &mut errors);
+        if errors.mismatches.is_empty() && errors.quotations.is_empty() && errors.definitions.is_empty() {
+            Ok(arited)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Carry on past mismatches, so that all of them are found in one go
+    ///
+    /// `known` is what has been pushed so far in the enclosing composition. Quotations
+    /// are followed through it up to the combinators that run them.
+    fn infer(e: Expression, words: &Words, known: &mut Vec<Option<Quoted>>, errors: &mut ArityError) -> Self {
         use self::Expression::*;
 
         match e {
-            Composition(v) => {
-                let comp: Vec<Arited> = v.into_iter().map(Arited::from_expression).collect();
+            Composition(v, span) => {
+                let comp: Vec<Arited> = v.into_iter().map(|e| Arited::infer(e, words, known, errors)).collect();
                 let arity = comp.iter().fold(Arity(0, 0), |ar, e| ar.compose(e.arity()));
-                Arited::Composition(comp, arity)
+                Arited::Composition(comp, arity, span)
             },
             // That's code duplication. I basically just used copy-paste here
             // No, I'm not going to refactor this
-            Concatenation(v) => {
-                let conc: Vec<Arited> = v.into_iter().map(Arited::from_expression).collect();
+            Concatenation(v, span) => {
+                let conc: Vec<Arited> = v.into_iter().map(|e| Arited::infer(e, words, &mut vec![], errors)).collect();
                 let arity = conc.iter().fold(Arity(0, 0), |ar, e| ar.concat(e.arity()));
-                Arited::Concatenation(conc, arity)
+                pass(known, arity);
+                Arited::Concatenation(conc, arity, span)
             },
-            Question(c, a) => {
-                let c = Arited::from_expression(*c);
-                let a = Arited::from_expression(*a);
+            Question(c, a, span) => {
+                let c = Arited::infer(*c, words, &mut vec![], errors);
+                let a = Arited::infer(*a, words, &mut vec![], errors);
                 let c_ar = c.arity();
-                if c_ar != a.arity() { panic!("Branch arity error") }
+                if c_ar != a.arity() {
+                    errors.mismatches.push(BranchMismatch {
+                        question: span,
+                        cons: (c_ar, c.span()),
+                        alter: (a.arity(), a.span()),
+                    })
+                }
 
-                Arited::Question(Box::new(c), Box::new(a), Arity(c_ar.0 + 1, c_ar.1))
+                let arity = Arity(c_ar.0 + 1, c_ar.1);
+                pass(known, arity);
+                Arited::Question(Box::new(c), Box::new(a), arity, span)
             },
-            InfixLeft(e, op) => {
-                let e = Arited::from_expression(*e);
-                let op = Arited::from_expression(*op);
+            InfixLeft(e, op, span) => {
+                let e = Arited::infer(*e, words, &mut vec![], errors);
+                let op = Arited::infer(*op, words, &mut vec![], errors);
 
-                let id_n = Arited::infix_id(&op, &e);
+                let id_n = Arited::infix_id(&op, &e, span);
                 let conc_ar = e.arity().concat(id_n.arity());
                 let comp_ar = conc_ar.compose(op.arity());
 
+                pass(known, comp_ar);
                 Arited::Composition(vec![
-                    Arited::Concatenation(vec![e, id_n], conc_ar), op
-                ], comp_ar)
+                    Arited::Concatenation(vec![e, id_n], conc_ar, span), op
+                ], comp_ar, span)
             },
-            InfixRight(op, e) => {
-                let e = Arited::from_expression(*e);
-                let op = Arited::from_expression(*op);
+            InfixRight(op, e, span) => {
+                let e = Arited::infer(*e, words, &mut vec![], errors);
+                let op = Arited::infer(*op, words, &mut vec![], errors);
 
-                let id_n = Arited::infix_id(&op, &e);
+                let id_n = Arited::infix_id(&op, &e, span);
                 let conc_ar = id_n.arity().concat(e.arity());
                 let comp_ar = conc_ar.compose(op.arity());
 
+                pass(known, comp_ar);
                 Arited::Composition(vec![
-                    Arited::Concatenation(vec![id_n, e], conc_ar), op
-                ], comp_ar)
+                    Arited::Concatenation(vec![id_n, e], conc_ar, span), op
+                ], comp_ar, span)
             },
-            Word(w) => {
-                let ar = word_arity(&w);
-                Arited::Word(w, ar)
+            Word(w, span) => Arited::word(w, span, known, errors),
+            Quotation(q, span) => {
+                let q = Box::new(Arited::infer(*q, words, &mut vec![], errors));
+                known.push(Some(Quoted { arity: q.arity(), span }));
+                Arited::Quotation(q, span)
             },
-            Quotation(q) => {
-                let q_ar = Box::new(Arited::from_expression(*q));
-                Arited::Quotation(q_ar)
+            Integer(i, span) => {
+                known.push(None);
+                Arited::Integer(i, span)
             },
-            Integer(i) => Arited::Integer(i),
-            Float(f) => Arited::Float(f),
-            String(s) => Arited::String(s),
-            Nop => Arited::IdN(0),
+            Rational(r, span) => {
+                known.push(None);
+                Arited::Rational(r, span)
+            },
+            Float(f, span) => {
+                known.push(None);
+                Arited::Float(f, span)
+            },
+            String(s, span) => {
+                known.push(None);
+                Arited::String(s, span)
+            },
+            Nop(span) => Arited::IdN(0, span),
+            Call(name, span) => {
+                let defined = match words.get(&name) {
+                    Some(Some(defined)) => defined.clone(),
+                    Some(None) => {
+                        errors.definitions.push(DefinitionError::Undeclared(name.clone(), span));
+                        Defined::unknown(Arity(0, 0))
+                    },
+                    None => {
+                        errors.definitions.push(DefinitionError::Undefined(name.clone(), span));
+                        Defined::unknown(Arity(0, 0))
+                    },
+                };
+                // Quotations that the body leaves are as good as pushed here
+                pass(known, Arity(defined.arity.0, 0));
+                known.extend(defined.leaves);
+                Arited::Call(name, defined.arity, span)
+            },
+            Definitions(defs, main, span) => {
+                let mut words = words.clone();
+                let mut defined = vec![];
+                for d in &defs {
+                    if defined.contains(&&d.name) {
+                        errors.definitions.push(DefinitionError::Duplicate(d.name.clone(), d.span));
+                    }
+                    defined.push(&d.name);
+                    words.insert(d.name.clone(), d.declared.map(Defined::unknown));
+                }
+
+                let mut arited = vec![];
+                for d in defs {
+                    let mut pushed = vec![];
+                    let body = Arited::infer(d.body, &words, &mut pushed, errors);
+                    match d.declared {
+                        Some(declared) if declared != body.arity() => {
+                            errors.definitions.push(DefinitionError::Declared {
+                                name: d.name.clone(),
+                                declared,
+                                body: body.arity(),
+                                span: d.span,
+                            })
+                        },
+                        Some(_) => (),
+                        None => { words.insert(d.name.clone(), Some(Defined::body(&body, &pushed))); },
+                    }
+                    arited.push(Definition { name: d.name, declared: d.declared, body, span: d.span });
+                }
+                let main = Arited::infer(*main, &words, known, errors);
+                Arited::Definitions(arited, Box::new(main), span)
+            },
+        }
+    }
+
+    /// Combinators take as many values as the quotations they run, when these are known
+    fn word(w: Word, span: Span, known: &mut Vec<Option<Quoted>>, errors: &mut ArityError) -> Self {
+        let mut check = |q: Option<Quoted>, fits: fn(Arity) -> bool, wanted| match q {
+            Some(q) if !fits(q.arity) => {
+                errors.quotations.push(QuotationMismatch {
+                    combinator: (w, span),
+                    quotation: (q.arity, q.span),
+                    wanted,
+                });
+                None
+            },
+            q => q.map(|q| q.arity),
+        };
+        let loops = |Arity(i, o): Arity| o == i + 1;
+        let keeps = |Arity(i, o): Arity| o == i;
+
+        let arity = match w {
+            self::Word::Apply => match nth_known(known, 0) {
+                Some(q) => Arity(q.arity.0 + 1, q.arity.1),
+                None => word_arity(&w),
+            },
+            self::Word::Rec => match check(nth_known(known, 0), loops, "n → n+1") {
+                Some(Arity(n, _)) => Arity(n + 1, n),
+                None => word_arity(&w),
+            },
+            self::Word::Times => match check(nth_known(known, 1), keeps, "n → n") {
+                Some(Arity(n, _)) => Arity(n + 2, n),
+                None => word_arity(&w),
+            },
+            self::Word::While => {
+                let cond = check(nth_known(known, 1), loops, "n → n+1");
+                let body = check(nth_known(known, 0), keeps, "n → n");
+                match (cond, body) {
+                    (Some(Arity(m, _)), Some(Arity(n, _))) => Arity(m.max(n) + 2, m.max(n)),
+                    _ => word_arity(&w),
+                }
+            },
+            _ => word_arity(&w),
+        };
+        shuffle(w, arity, known);
+        Arited::Word(w, arity, span)
+    }
+
+    /// Follow what this leaves on top of `known` again, calling `words`
+    fn leaves(&self, words: &Words, known: &mut Vec<Option<Quoted>>) {
+        match *self {
+            Arited::Composition(ref comp, _, _) => {
+                for e in comp {
+                    e.leaves(words, known)
+                }
+            },
+            Arited::Word(w, arity, _) => shuffle(w, arity, known),
+            Arited::Quotation(ref q, span) => known.push(Some(Quoted { arity: q.arity(), span })),
+            Arited::Integer(..) | Arited::Rational(..) | Arited::Float(..) | Arited::String(..) => known.push(None),
+            Arited::Call(ref name, arity, _) => match words.get(name) {
+                Some(Some(defined)) => {
+                    pass(known, Arity(arity.0, 0));
+                    known.extend(defined.leaves.iter().cloned());
+                },
+                _ => pass(known, arity),
+            },
+            ref other => pass(known, other.arity()),
         }
     }
 
-    fn infix_id(infix: &Arited, expr: &Arited) -> Self {
+    /// With every call renamed by `rename`, and every span replaced by `span` if there is one
+    fn rewritten(self, rename: &dyn Fn(String) -> String, span: Option<Span>) -> Self {
+        use self::Arited::*;
+        let at = |s: Span| span.unwrap_or(s);
+        let one = |e: Box<Arited>| Box::new(e.rewritten(rename, span));
+        let all = |v: Vec<Arited>| v.into_iter().map(|e| e.rewritten(rename, span)).collect();
+        match self {
+            Composition(v, ar, s) => Composition(all(v), ar, at(s)),
+            Concatenation(v, ar, s) => Concatenation(all(v), ar, at(s)),
+            Question(c, a, ar, s) => Question(one(c), one(a), ar, at(s)),
+            Word(w, ar, s) => Word(w, ar, at(s)),
+            Integer(i, s) => Integer(i, at(s)),
+            Rational(r, s) => Rational(r, at(s)),
+            Float(f, s) => Float(f, at(s)),
+            String(st, s) => String(st, at(s)),
+            Quotation(q, s) => Quotation(one(q), at(s)),
+            IdN(n, s) => IdN(n, at(s)),
+            Call(name, ar, s) => Call(rename(name), ar, at(s)),
+            Definitions(defs, main, s) => Definitions(
+                defs.into_iter().map(|d| d.rewritten(rename, span)).collect(),
+                one(main),
+                at(s),
+            ),
+        }
+    }
+
+    fn infix_id(infix: &Arited, expr: &Arited, span: Span) -> Self {
         let ar_inf = infix.arity();
         let ar_exp = expr.arity();
-        let n = if ar_inf.0 > ar_exp.1 { ar_inf.0 - ar_exp.1 } else { 0 };
-        Arited::IdN(n)
+        let n = ar_inf.0.saturating_sub(ar_exp.1);
+        Arited::IdN(n, span)
     }
 
     pub fn arity(&self) -> Arity {
         use self::Arited::*;
         match *self {
-            Composition(_, ar)
-            | Concatenation(_, ar)
-            | Question(_, _, ar)
-            | Word(_, ar) => ar,
-            IdN(n) => Arity(n, n),
+            Composition(_, ar, _)
+            | Concatenation(_, ar, _)
+            | Question(_, _, ar, _)
+            | Word(_, ar, _)
+            | Call(_, ar, _) => ar,
+            IdN(n, _) => Arity(n, n),
+            Definitions(_, ref main, _) => main.arity(),
             _ => Arity(0, 1)
         }
     }
+
+    pub fn span(&self) -> Span {
+        use self::Arited::*;
+        match *self {
+            Composition(_, _, span)
+            | Concatenation(_, _, span)
+            | Question(_, _, _, span)
+            | Word(_, _, span)
+            | Integer(_, span)
+            | Rational(_, span)
+            | Float(_, span)
+            | String(_, span)
+            | Quotation(_, span)
+            | IdN(_, span)
+            | Call(_, _, span)
+            | Definitions(_, _, span) => span,
+        }
+    }
+}
+
+impl Arited {
+    /// Written so that it can be a part of a concatenation
+    fn fmt_simple(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Arited::Composition(..) | Arited::Concatenation(..) | Arited::IdN(0, _) | Arited::Definitions(..) => {
+                write!(f, "({})", self)
+            },
+            _ => write!(f, "{}", self),
+        }
+    }
+}
+
+/// Back to source, give or take some parentheses
+///
+/// Infix forms come back as the concatenations they are made of.
+impl fmt::Display for Arited {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Arited::*;
+        match *self {
+            Composition(ref comp, _, _) => {
+                for (i, e) in comp.iter().enumerate() {
+                    if i > 0 { write!(f, " ")? }
+                    match *e {
+                        Composition(..) => write!(f, "({})", e)?,
+                        _ => write!(f, "{}", e)?,
+                    }
+                }
+                Ok(())
+            },
+            Concatenation(ref conc, _, _) => {
+                for (i, e) in conc.iter().enumerate() {
+                    if i > 0 { write!(f, ";")? }
+                    e.fmt_simple(f)?;
+                }
+                Ok(())
+            },
+            Question(ref c, ref a, _, _) => write!(f, "?{}:{}.", c, a),
+            Word(w, _, _) => write!(f, "{}", w.symbol()),
+            Integer(ref i, _) => write!(f, "{}", integer_literal(i)),
+            Rational(ref r, _) => write!(f, "{}", rational_literal(r)),
+            Float(x, _) => write!(f, "{}", float_literal(x)),
+            String(ref s, _) => write!(f, "{}", string_literal(s)),
+            Quotation(ref q, _) => write!(f, "[{}]", q),
+            IdN(n, _) => {
+                let ids: Vec<&str> = (0..n).map(|_| self::Word::Id.symbol()).collect();
+                write!(f, "{}", ids.join(";"))
+            },
+            Call(ref name, _, _) => write!(f, "{}", name),
+            Definitions(ref defs, ref main, _) => {
+                for d in defs {
+                    match d.declared {
+                        Some(arity) => write!(f, "{} {} ← {} ⋄ ", d.name, arity, d.body)?,
+                        None => write!(f, "{} ← {} ⋄ ", d.name, d.body)?,
+                    }
+                }
+                write!(f, "{}", main)
+            },
+        }
+    }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::super::parser;
-    use super::{Arited, Arity, Word};
+    use super::{Arited, Arity, ArityError, Definition, DefinitionError, Expression, Span, Word};
+    use super::Expression::*;
+
+    /// Spans are tested on their own, so trees are compared without them
+    pub(crate) fn unspanned(expr: Expression) -> Expression {
+        let s = Span::default();
+        let all = |v: Vec<Expression>| v.into_iter().map(unspanned).collect();
+        let one = |e: Box<Expression>| Box::new(unspanned(*e));
+        match expr {
+            Composition(v, _) => Composition(all(v), s),
+            Concatenation(v, _) => Concatenation(all(v), s),
+            Question(c, a, _) => Question(one(c), one(a), s),
+            Word(w, _) => Word(w, s),
+            Integer(i, _) => Integer(i, s),
+            Rational(r, _) => Rational(r, s),
+            Float(f, _) => Float(f, s),
+            String(st, _) => String(st, s),
+            Quotation(q, _) => Quotation(one(q), s),
+            Nop(_) => Nop(s),
+            InfixLeft(l, r, _) => InfixLeft(one(l), one(r), s),
+            InfixRight(l, r, _) => InfixRight(one(l), one(r), s),
+            Call(name, _) => Call(name, s),
+            Definitions(defs, main, _) => Definitions(
+                defs.into_iter().map(|d| Definition { body: unspanned(d.body), span: s, ..d }).collect(),
+                one(main),
+                s,
+            ),
+        }
+    }
 
     #[test] fn test_simple() {
-        let expr = Arited::from_expression(parser::parse("×⎕".into()).unwrap());
+        let expr = Arited::from_expression(unspanned(parser::parse("×⎕").unwrap())).unwrap();
+        let s = Span::default();
         assert_eq!(
             expr,
             Arited::Composition(
                 vec![
-                    Arited::Word(Word::Prod, Arity(2, 1)),
-                    Arited::Word(Word::Print, Arity(1, 0))
+                    Arited::Word(Word::Prod, Arity(2, 1), s),
+                    Arited::Word(Word::Print, Arity(1, 0), s)
                 ],
-                Arity(2, 0)
+                Arity(2, 0),
+                s
             )
         );
     }
 
     #[test] fn test_infix() {
-        let expr = Arited::from_expression(parser::parse("×`+`×".into()).unwrap());
+        let expr = Arited::from_expression(unspanned(parser::parse("×`+`×").unwrap())).unwrap();
+        let s = Span::default();
         assert_eq!(
             expr,
             Arited::Composition(
                 vec![
                     Arited::Concatenation(
                         vec![
-                            Arited::Word(Word::Prod, Arity(2, 1)),
-                            Arited::Word(Word::Prod, Arity(2, 1))
+                            Arited::Word(Word::Prod, Arity(2, 1), s),
+                            Arited::Word(Word::Prod, Arity(2, 1), s)
                         ],
-                        Arity(4, 2)
+                        Arity(4, 2),
+                        s
                     ),
-                    Arited::Word(Word::Plus, Arity(2, 1))
+                    Arited::Word(Word::Plus, Arity(2, 1), s)
                 ],
-                Arity(4, 1)
+                Arity(4, 1),
+                s
             )
         );
     }
 
     #[test] fn test_zero() {
-        let expr = Arited::from_expression(parser::parse("2 2 3 3 ×`+`×⎕".into()).unwrap());
+        let expr = Arited::from_expression(parser::parse("2 2 3 3 ×`+`×⎕").unwrap()).unwrap();
         match expr {
-            Arited::Composition(_, Arity(0, 0)) => (),
+            Arited::Composition(_, Arity(0, 0), _) => (),
             _ => panic!("Expr is not composition of arity null: {:?}", expr),
         }
     }
+
+    #[test] fn test_spans() {
+        let expr = Arited::from_expression(parser::parse("1 2\n  + ⎕").unwrap()).unwrap();
+        match expr {
+            Arited::Composition(ref comp, _, _) => {
+                let plus = comp[2].span();
+                assert_eq!((plus.start, plus.end, plus.line, plus.column), (6, 7, 2, 3));
+            },
+            _ => panic!("Expr is not a composition: {:?}", expr),
+        }
+    }
+
+    #[test] fn test_default_excerpt() {
+        assert_eq!(Span::default().excerpt("1 +"), "  |\n0 | 1 +\n  | ^");
+    }
+
+    #[test] fn test_display() {
+        let shown = |code: &str| Arited::from_expression(parser::parse(code).unwrap()).unwrap().to_string();
+        assert_eq!(shown("1 2 +"), "1 2 +");
+        assert_eq!(shown("¯3r4 ¯1.5 1e100 '\\n\\'' (?1:2.·)"), "¯3r4 ¯1.5 1e100 '\\n\\'' (?1:2. ·)");
+        assert_eq!(shown("1 ⇈;(2 +) ×"), "1 ⇈;(2 +) ×");
+        assert_eq!(shown("×`+`×"), "×;× +");
+        assert_eq!(shown("1`+`"), "1;· +");
+        assert_eq!(shown("[[] 1]"), "[[] 1]");
+        assert_eq!(shown("f 1 → 1 ← ⇈ × ⋄ g←f⋄ 2 g"), "f 1 → 1 ← ⇈ × ⋄ g ← f ⋄ 2 g");
+        for code in &["1 2 +", "⍬1,[⇈ ×],", "1 ⇈;(2 +) ×", "×`+`×", "⊤(?'a':'b'.⎕)", "f ← 1;2 ⋄ f"] {
+            let once = shown(code);
+            assert_eq!(shown(&once), once);
+        }
+    }
+
+    fn check(code: &str) -> Result<Arited, ArityError> {
+        Arited::from_expression(parser::parse(code).unwrap())
+    }
+
+    #[test] fn test_quotation_arity() {
+        let error = check("1 [+] ∇").unwrap_err();
+        assert_eq!(error.quotations.len(), 1);
+        let m = &error.quotations[0];
+        assert_eq!(m.combinator.0, Word::Rec);
+        assert_eq!((m.quotation.0, m.quotation.1.column), (Arity(2, 1), 3));
+        assert_eq!(m.to_string(), "quotation at 1:3 is 2 → 1, but `∇` at 1:7 wants n → n+1");
+
+        // Followed through shuffling and grouping
+        assert!(check("[+] 1 ↔ ∇").is_err());
+        assert!(check("1 [+] ⇈ ↓ ∇").is_err());
+        assert!(check("1 ([+] ·) ∇").is_err());
+        assert_eq!(check("1 [↓] 3 ⍣").unwrap_err().quotations[0].wanted, "n → n");
+        assert_eq!(check("1 [1 <] [⇈] ⍤").unwrap_err().quotations.len(), 2);
+        assert!(check("[1 [+] ∇]").is_err());
+
+        // Combinators take what their quotations take
+        assert_eq!(check("1 2 [+] ⍎").unwrap().arity(), Arity(0, 1));
+        assert_eq!(check("[+] ⍎").unwrap().arity(), Arity(2, 1));
+        assert_eq!(check("0 10 [⇈ +;(1 −) ⇈ 0 >] ∇").unwrap().arity(), Arity(0, 2));
+        assert_eq!(check("[⇈;⇈ ↓;↔;· +] 10 ⍣").unwrap().arity(), Arity(2, 2));
+        assert_eq!(check("[⇈ 100 <] [2 ×] ⍤").unwrap().arity(), Arity(1, 1));
+        // And what they cannot see is left for the run
+        assert_eq!(check("·;[+] ∇").unwrap().arity(), Arity(1, 1));
+    }
+
+    #[test] fn test_definitions() {
+        assert_eq!(check("sq ← ⇈ × ⋄ sq").unwrap().arity(), Arity(1, 1));
+        assert_eq!(check("sq ← ⇈ × ⋄ sum ← + ⋄ sq;sq sum").unwrap().arity(), Arity(2, 1));
+        assert_eq!(check("fact 1 → 1 ← ⇈ 1 > (?⇈ 1 − fact ×:↓ 1. ·) ⋄ 5 fact").unwrap().arity(), Arity(0, 1));
+        // Quotations that definitions leave are followed out of them
+        assert_eq!(check("sumq ← [⇈ +;(1 −) ⇈ 0 >] ⋄ 0 10 sumq ∇").unwrap().arity(), Arity(0, 2));
+        assert_eq!(check("q ← 1 [2 ×] ⋄ q 3 ⍣").unwrap().arity(), Arity(0, 1));
+        assert_eq!(check("add ← [+] ⋄ 1 add ∇").unwrap_err().quotations[0].wanted, "n → n+1");
+        // Calls made early need to know their arities up front
+        assert!(check("even 1 → 1 ← ⇈ 0 = (?↓ ⊤:1 − odd. ·) ⋄ odd 1 → 1 ← ⇈ 0 = (?↓ ⊥:1 − even. ·) ⋄ 7 even").is_ok());
+
+        let errors = |code: &str| check(code).unwrap_err().definitions;
+        // The call or the definition at fault, from `start` to `end` of `code`
+        let at = |code: &str, start, end| Span::new(start, end, code);
+        let code = "1 nope";
+        assert_eq!(errors(code), vec![DefinitionError::Undefined("nope".into(), at(code, 2, 6))]);
+        let code = "loop ← loop ⋄ loop";
+        assert_eq!(errors(code), vec![DefinitionError::Undeclared("loop".into(), at(code, 9, 13))]);
+        let code = "a ← b ⋄ b ← 1 ⋄ a";
+        assert_eq!(errors(code), vec![DefinitionError::Undeclared("b".into(), at(code, 6, 7))]);
+        let code = "a ← 1 ⋄ a ← 2 ⋄ a";
+        assert_eq!(errors(code), vec![DefinitionError::Duplicate("a".into(), at(code, 12, 23))]);
+        let e = &errors("dup 1 → 1 ← ⇈ ⋄ 1 dup")[0];
+        assert_eq!(e.to_string(), "`dup` at 1:1 is declared 1 → 1, but its body is 1 → 2");
+    }
+
+    #[test] fn test_all_mismatches() {
+        let error = Arited::from_expression(
+            parser::parse("1 2 = (?1 2:3.·) ⎕ 1 2 = (?⎕:·.·)").unwrap()
+        ).unwrap_err();
+        assert_eq!(error.mismatches.len(), 2);
+        assert_eq!(error.mismatches[0].cons.0, Arity(0, 2));
+        assert_eq!(error.mismatches[0].alter.0, Arity(0, 1));
+        assert_eq!(error.mismatches[1].question.column, 27);
+    }
 }
@@ -1,25 +1,179 @@
-use super::ast::{Arity, Arited, Word};
+use super::ast::{self, Arity, Arited, Definition, Span, Word};
 
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::{FromPrimitive, Signed, ToPrimitive, Zero};
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
 use std::convert::From;
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+
+/// How many values from the top of the stack a `Fault` keeps
+const SNAPSHOT_DEPTH: usize = 8;
+/// How many chars of each of them
+const SNAPSHOT_WIDTH: usize = 40;
+/// How deep calls can nest, well before the native stack runs out even in a debug build
+const MAX_DEPTH: usize = 1000;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     TypeError,
     ListError,
-    PrintError,
     ArityError,
+    StackUnderflow { needed: usize, available: usize },
+    IoError(io::ErrorKind),
+    /// Input that was supposed to be a number, but isn't
+    InputError,
+    DivisionByZero,
+    /// An integer that doesn't fit
+    Overflow,
+    /// A call to a word the machine has no definition for
+    UnknownWord,
+    /// Calls nested deeper than the machine allows, most likely a recursion that never ends
+    TooDeep,
+}
+
+/// The part of the program that raised an error
+#[derive(Debug, Clone, PartialEq)]
+pub enum Culprit {
+    Word(Word),
+    /// A call to a defined word, by its name
+    Call(String),
+    /// The condition of a `?...:...` question
+    Question,
+    /// A `;` concatenation, which takes its arguments all at once
+    Concatenation,
+    /// The program as a whole
+    Program,
+}
+
+/// A runtime error together with everything we know about where it happened
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fault {
+    pub error: Error,
+    pub culprit: Option<Culprit>,
+    /// Where the culprit is in the source
+    pub span: Option<Span>,
+    /// Types of the operands the culprit choked on
+    pub operands: Vec<&'static str>,
+    /// Top of the stack at the moment of failure, rendered, the topmost value last
+    pub stack: Vec<String>,
+}
+
+impl Fault {
+    pub fn new(error: Error) -> Self {
+        Fault {
+            error,
+            culprit: None,
+            span: None,
+            operands: vec![],
+            stack: vec![],
+        }
+    }
+
+    /// Keeps the operands, which the culprit has already popped, to put back on the snapshot
+    fn with_operands(mut self, operands: &[&Data]) -> Self {
+        self.operands = operands.iter().map(|d| d.type_name()).collect();
+        self.stack = operands.iter().map(|d| abbreviate(d)).collect();
+        self
+    }
+
+    /// Blame `culprit`, unless someone deeper down is already blamed
+    fn raised_by(mut self, culprit: Culprit, span: Span, stack: &[Data]) -> Self {
+        if self.culprit.is_none() {
+            let from = stack.len().saturating_sub(SNAPSHOT_DEPTH);
+            let mut snapshot: Vec<String> = stack[from..].iter().map(abbreviate).collect();
+            snapshot.append(&mut self.stack);
+            let from = snapshot.len().saturating_sub(SNAPSHOT_DEPTH);
+            self.culprit = Some(culprit);
+            self.span = Some(span);
+            self.stack = snapshot.split_off(from);
+        }
+        self
+    }
+}
+
+fn abbreviate(value: &Data) -> String {
+    let shown = value.to_string();
+    if shown.chars().count() > SNAPSHOT_WIDTH {
+        let mut short: String = shown.chars().take(SNAPSHOT_WIDTH - 1).collect();
+        short.push('…');
+        short
+    } else {
+        shown
+    }
+}
+
+impl From<Error> for Fault {
+    fn from(error: Error) -> Fault {
+        Fault::new(error)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::TypeError => write!(f, "wrong types of operands"),
+            Error::ListError => write!(f, "list too short for the index or count"),
+            Error::ArityError => write!(f, "arity mismatch"),
+            Error::StackUnderflow { needed, available } =>
+                write!(f, "stack underflow: needed {}, but only {} available", needed, available),
+            Error::IoError(kind) => write!(f, "I/O error: {}", io::Error::from(kind)),
+            Error::InputError => write!(f, "input is not a number"),
+            Error::DivisionByZero => write!(f, "division by zero"),
+            Error::Overflow => write!(f, "integer overflow"),
+            Error::UnknownWord => write!(f, "unknown word"),
+            Error::TooDeep => write!(f, "calls nested too deeply"),
+        }
+    }
+}
+
+impl fmt::Display for Culprit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Culprit::Word(w) => write!(f, "`{}`", w.symbol()),
+            Culprit::Call(ref name) => write!(f, "`{}`", name),
+            Culprit::Question => write!(f, "the condition of `?`"),
+            Culprit::Concatenation => write!(f, "`;`"),
+            Culprit::Program => write!(f, "the program"),
+        }
+    }
 }
 
-impl From<!> for Error {
-    fn from(_: !) -> Error {
-        unreachable!()
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.error)?;
+        if let Some(ref culprit) = self.culprit {
+            write!(f, " in {}", culprit)?;
+        }
+        if let Some(span) = self.span {
+            write!(f, " at {}:{}", span.line, span.column)?;
+        }
+        if !self.operands.is_empty() {
+            write!(f, ", operands: {}", self.operands.join(", "))?;
+        }
+        writeln!(f)?;
+        write!(f, "  stack (top last):")?;
+        if self.stack.len() == SNAPSHOT_DEPTH {
+            write!(f, " …")?;
+        }
+        for v in &self.stack {
+            write!(f, " {}", v)?;
+        }
+        Ok(())
     }
 }
 
-#[derive(Debug, Clone)]
-enum Data {
-    Integer(i64),
+/// A value on the stack
+#[derive(Debug, Clone, PartialEq)]
+pub enum Data {
+    Integer(BigInt),
+    /// Never with a denominator of one: those are integers
+    Rational(BigRational),
     Float(f64),
     String(String),
     Bool(bool),
@@ -27,308 +181,1262 @@ enum Data {
     Quotation(Box<Arited>)
 }
 
-#[derive(Debug)]
-pub struct Machine {
+impl Data {
+    fn type_name(&self) -> &'static str {
+        match *self {
+            Data::Integer(_) => "Integer",
+            Data::Rational(_) => "Rational",
+            Data::Float(_) => "Float",
+            Data::String(_) => "String",
+            Data::Bool(_) => "Bool",
+            Data::List(_) => "List",
+            Data::Quotation(_) => "Quotation",
+        }
+    }
+
+    fn is_number(&self) -> bool {
+        matches!(*self, Data::Integer(_) | Data::Rational(_) | Data::Float(_))
+    }
+
+    /// What `⎕` prints: strings as they are, everything else in the canonical form
+    pub fn show(&self) -> String {
+        match *self {
+            Data::String(ref s) => s.clone(),
+            ref v => v.to_string(),
+        }
+    }
+
+    /// An exact number, as an integer if it is one
+    fn exact(r: BigRational) -> Data {
+        if r.is_integer() {
+            Data::Integer(r.to_integer())
+        } else {
+            Data::Rational(r)
+        }
+    }
+}
+
+/// The canonical form: esobsc source that gives the value back
+///
+/// Lists are built up from `⍬`, like `⍬1,2,3,`.
+impl fmt::Display for Data {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Data::Integer(ref i) => write!(f, "{}", ast::integer_literal(i)),
+            Data::Rational(ref r) => write!(f, "{}", ast::rational_literal(r)),
+            Data::Float(x) => write!(f, "{}", ast::float_literal(x)),
+            Data::String(ref s) => write!(f, "{}", ast::string_literal(s)),
+            Data::Bool(b) => write!(f, "{}", if b { Word::True } else { Word::False }.symbol()),
+            Data::List(ref vs) => {
+                write!(f, "{}", Word::Zilde.symbol())?;
+                for v in vs {
+                    write!(f, "{}{}", v, Word::Comma.symbol())?;
+                }
+                Ok(())
+            },
+            Data::Quotation(ref q) => write!(f, "[{}]", q),
+        }
+    }
+}
+
+/// Two numbers brought to a common type
+enum Numbers {
+    Integers(BigInt, BigInt),
+    Rationals(BigRational, BigRational),
+    Floats(f64, f64),
+}
+
+fn to_float(n: &Data) -> f64 {
+    // Too big for a float is infinitely big
+    let f = match *n {
+        Data::Integer(ref i) => i.to_f64(),
+        Data::Rational(ref r) => r.to_f64(),
+        Data::Float(f) => Some(f),
+        _ => None,
+    };
+    f.unwrap_or(f64::NAN)
+}
+
+fn to_rational(n: Data) -> BigRational {
+    match n {
+        Data::Integer(i) => BigRational::from_integer(i),
+        Data::Rational(r) => r,
+        _ => unreachable!("IIE: Not an exact number"),
+    }
+}
+
+/// Integers meeting rationals become rationals, anything meeting floats becomes a float
+fn promote(left: Data, right: Data) -> Result<Numbers, Fault> {
+    match (left, right) {
+        (Data::Integer(l), Data::Integer(r)) => Ok(Numbers::Integers(l, r)),
+        (l, r) => {
+            if !l.is_number() || !r.is_number() {
+                Err(Fault::new(Error::TypeError).with_operands(&[&l, &r]))
+            } else if let (&Data::Float(_), _) | (_, &Data::Float(_)) = (&l, &r) {
+                Ok(Numbers::Floats(to_float(&l), to_float(&r)))
+            } else {
+                Ok(Numbers::Rationals(to_rational(l), to_rational(r)))
+            }
+        },
+    }
+}
+
+/// Numbers by value, strings, Booleans and lists lexicographically
+///
+/// `None` is for NaNs, which are in no order with anything. Values of different types, and
+/// quotations, have no order at all.
+fn order(left: &Data, right: &Data) -> Result<Option<Ordering>, Fault> {
+    match (left, right) {
+        (Data::String(l), Data::String(r)) => Ok(Some(l.cmp(r))),
+        (Data::Bool(l), Data::Bool(r)) => Ok(Some(l.cmp(r))),
+        (Data::List(l), Data::List(r)) => {
+            for (l, r) in l.iter().zip(r) {
+                match order(l, r)? {
+                    Some(Ordering::Equal) => (),
+                    ordering => return Ok(ordering),
+                }
+            }
+            Ok(Some(l.len().cmp(&r.len())))
+        },
+        (l, r) if l.is_number() && r.is_number() => Ok(match promote(l.clone(), r.clone())? {
+            Numbers::Integers(l, r) => Some(l.cmp(&r)),
+            Numbers::Rationals(l, r) => Some(l.cmp(&r)),
+            Numbers::Floats(l, r) => l.partial_cmp(&r),
+        }),
+        (l, r) => Err(Fault::new(Error::TypeError).with_operands(&[l, r])),
+    }
+}
+
+/// Structural equality, where numbers are equal by value and values of different types are just unequal
+fn equal(left: &Data, right: &Data) -> bool {
+    match (left, right) {
+        (Data::List(l), Data::List(r)) => {
+            l.len() == r.len() && l.iter().zip(r).all(|(l, r)| equal(l, r))
+        },
+        // Whatever their place in the source, quotations that read the same are the same
+        (Data::Quotation(l), Data::Quotation(r)) => l.to_string() == r.to_string(),
+        (l, r) if l.is_number() && r.is_number() => {
+            matches!(order(l, r), Ok(Some(Ordering::Equal)))
+        },
+        (l, r) => l == r,
+    }
+}
+
+/// An integral float as an integer, unless it is infinite or not a number at all
+fn float_to_integer(f: f64) -> Result<Data, Error> {
+    BigInt::from_f64(f).map(Data::Integer).ok_or(Error::Overflow)
+}
+
+/// The stack machine programs run on
+///
+/// Programs read from `input` and print to `output`, stdin and stdout unless told otherwise.
+pub struct Machine<'io> {
     stack: Vec<Data>,
     retained: Vec<Data>,
+    /// Defined words, which stay defined for the rest of the machine's life
+    words: HashMap<String, Rc<Arited>>,
+    /// How many calls are running, one inside the other
+    depth: usize,
+    input: Box<dyn BufRead + 'io>,
+    output: Box<dyn Write + 'io>,
+}
+
+impl<'io> fmt::Debug for Machine<'io> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Machine")
+            .field("stack", &self.stack)
+            .field("retained", &self.retained)
+            .field("words", &self.words.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Default for Machine<'static> {
+    fn default() -> Self {
+        Machine::new()
+    }
 }
 
-impl Machine {
+impl Machine<'static> {
     pub fn new() -> Self {
+        Machine::with_io(BufReader::new(io::stdin()), io::stdout())
+    }
+}
+
+impl<'io> Machine<'io> {
+    /// A machine that reads from `input` and prints to `output`
+    ///
+    /// ```
+    /// let mut out = vec![];
+    /// esobsc::run("'hi' ⎕", &mut esobsc::Machine::with_io(&b""[..], &mut out)).unwrap();
+    /// assert_eq!(out, b"hi");
+    /// ```
+    pub fn with_io<R, W>(input: R, output: W) -> Self
+        where R: BufRead + 'io, W: Write + 'io
+    {
         Machine {
             stack: vec![],
             retained: vec![],
+            words: HashMap::new(),
+            depth: 0,
+            input: Box::new(input),
+            output: Box::new(output),
         }
     }
 
-    fn retain(&mut self, n: usize) {
+    /// The stack, the topmost value last
+    pub fn stack(&self) -> &[Data] {
+        &self.stack
+    }
+
+    pub fn push(&mut self, value: Data) {
+        self.stack.push(value);
+    }
+
+    pub fn pop(&mut self) -> Option<Data> {
+        self.stack.pop()
+    }
+
+    fn retain(&mut self, n: usize) -> Result<(), Error> {
+        self.ensure(n)?;
         for _ in 0..n {
-            let v = self.pop_one();
+            let v = self.pop_one()?;
             self.retained.push(v);
         }
+        Ok(())
     }
 
-    fn restore(&mut self, n: usize) {
+    fn restore(&mut self, n: usize) -> Result<(), Error> {
+        if self.retained.len() < n {
+            return Err(Error::StackUnderflow { needed: n, available: self.retained.len() })
+        }
         for _ in 0..n {
             let v = self.retained.pop().expect("IIE: Retained underflow");
             self.stack.push(v);
         }
+        Ok(())
+    }
+
+    /// Check that there are at least `n` values to pop
+    fn ensure(&self, n: usize) -> Result<(), Error> {
+        if self.stack.len() < n {
+            Err(Error::StackUnderflow { needed: n, available: self.stack.len() })
+        } else {
+            Ok(())
+        }
     }
 
-    fn pop_one(&mut self) -> Data {
-        self.stack.pop().expect("IIE: Stack underflow")
+    fn pop_one(&mut self) -> Result<Data, Error> {
+        self.ensure(1)?;
+        Ok(self.stack.pop().expect("IIE: Stack underflow"))
     }
 
-    fn pop_two(&mut self) -> (Data, Data) {
-        let r = self.pop_one();
-        let l = self.pop_one();
-        (l, r)
+    fn pop_two(&mut self) -> Result<(Data, Data), Error> {
+        self.ensure(2)?;
+        let r = self.pop_one()?;
+        let l = self.pop_one()?;
+        Ok((l, r))
     }
 
-    fn push_integer(&mut self, int: i64) -> Result<(), !> {
+    fn push_integer(&mut self, int: BigInt) {
         self.stack.push(Data::Integer(int));
-        Ok(())
     }
 
-    fn push_float(&mut self, float: f64) -> Result<(), !> {
+    /// Push an exact result, as an integer if it is one
+    fn push_rational(&mut self, rational: BigRational) {
+        self.stack.push(Data::exact(rational));
+    }
+
+    fn push_float(&mut self, float: f64) {
         self.stack.push(Data::Float(float));
-        Ok(())
     }
 
-    fn push_string(&mut self, string: String) -> Result<(), !> {
+    fn push_string(&mut self, string: String) {
         self.stack.push(Data::String(string));
-        Ok(())
     }
 
     fn push_bool(&mut self, logic: bool) {
         self.stack.push(Data::Bool(logic));
     }
 
-    fn push_zilde(&mut self) -> Result<(), !> {
+    fn push_zilde(&mut self) {
         self.stack.push(Data::List(VecDeque::new()));
-        Ok(())
     }
 
-    fn push_quotation(&mut self, arited: Box<Arited>) -> Result<(), !> {
+    fn push_quotation(&mut self, arited: Box<Arited>) {
         self.stack.push(Data::Quotation(arited));
+    }
+
+    fn greater_than(&mut self) -> Result<(), Fault> {
+        let (left, right) = self.pop_two()?;
+        let ordering = order(&left, &right)?;
+        self.push_bool(ordering == Some(Ordering::Greater));
         Ok(())
     }
 
-    fn greater_than(&mut self) -> Result<(), Error> {
-        let (left, right) = self.pop_two();
-        match (left, right) {
-            (Data::Integer(l), Data::Integer(r)) => {
-                self.push_bool(l > r);
-            },
-            (Data::Float(l), Data::Float(r)) => {
-                self.push_bool(l > r);
-            },
-            _ => return Err(Error::TypeError),
+    fn lesser_than(&mut self) -> Result<(), Fault> {
+        let (left, right) = self.pop_two()?;
+        let ordering = order(&left, &right)?;
+        self.push_bool(ordering == Some(Ordering::Less));
+        Ok(())
+    }
+
+    fn equals(&mut self) -> Result<(), Fault> {
+        let (left, right) = self.pop_two()?;
+        self.push_bool(equal(&left, &right));
+        Ok(())
+    }
+
+    fn not_equals(&mut self) -> Result<(), Fault> {
+        let (left, right) = self.pop_two()?;
+        self.push_bool(!equal(&left, &right));
+        Ok(())
+    }
+
+    /// Apply `op` to the two Booleans on the top
+    fn logic<F>(&mut self, op: F) -> Result<(), Fault>
+        where F: FnOnce(bool, bool) -> bool
+    {
+        match self.pop_two()? {
+            (Data::Bool(l), Data::Bool(r)) => self.push_bool(op(l, r)),
+            (l, r) => return Err(Fault::new(Error::TypeError).with_operands(&[&l, &r])),
         }
         Ok(())
     }
 
-    fn lesser_than(&mut self) -> Result<(), Error> {
-        let (left, right) = self.pop_two();
-        match (left, right) {
-            (Data::Integer(l), Data::Integer(r)) => {
-                self.push_bool(l < r);
-            },
-            (Data::Float(l), Data::Float(r)) => {
-                self.push_bool(l < r);
-            },
-            _ => return Err(Error::TypeError),
+    fn not(&mut self) -> Result<(), Fault> {
+        match self.pop_one()? {
+            Data::Bool(b) => self.push_bool(!b),
+            v => return Err(Fault::new(Error::TypeError).with_operands(&[&v])),
         }
         Ok(())
     }
 
-    fn equals(&mut self) -> Result<(), Error> {
-        let (left, right) = self.pop_two();
-        match (left, right) {
-            (Data::Integer(l), Data::Integer(r)) => {
-                self.push_bool(l == r);
-            },
-            (Data::Float(l), Data::Float(r)) => {
-                self.push_bool(l == r);
-            },
-            _ => return Err(Error::TypeError),
+    fn plus(&mut self) -> Result<(), Fault> {
+        let (left, right) = self.pop_two()?;
+        match promote(left, right)? {
+            Numbers::Integers(l, r) => self.push_integer(l + r),
+            Numbers::Rationals(l, r) => self.push_rational(l + r),
+            Numbers::Floats(l, r) => self.push_float(l + r),
         }
         Ok(())
     }
 
-    fn plus(&mut self) -> Result<(), Error> {
-        let (left, right) = self.pop_two();
-        match (left, right) {
-            (Data::Integer(l), Data::Integer(r)) => {
-                self.push_integer(l + r)?
-            },
-            (Data::Float(l), Data::Float(r)) => {
-                self.push_float(l + r)?
-            },
-            _ => return Err(Error::TypeError),
+    fn minus(&mut self) -> Result<(), Fault> {
+        let (left, right) = self.pop_two()?;
+        match promote(left, right)? {
+            Numbers::Integers(l, r) => self.push_integer(l - r),
+            Numbers::Rationals(l, r) => self.push_rational(l - r),
+            Numbers::Floats(l, r) => self.push_float(l - r),
         }
         Ok(())
     }
 
-    fn minus(&mut self) -> Result<(), Error> {
-        let (left, right) = self.pop_two();
-        match (left, right) {
-            (Data::Integer(l), Data::Integer(r)) => {
-                self.push_integer(l - r)?
+    fn prod(&mut self) -> Result<(), Fault> {
+        let (left, right) = self.pop_two()?;
+        match promote(left, right)? {
+            Numbers::Integers(l, r) => self.push_integer(l * r),
+            Numbers::Rationals(l, r) => self.push_rational(l * r),
+            Numbers::Floats(l, r) => self.push_float(l * r),
+        }
+        Ok(())
+    }
+
+    /// Dividing exact numbers gives an exact number, maybe a rational one
+    fn div(&mut self) -> Result<(), Fault> {
+        let (left, right) = self.pop_two()?;
+        let blame = |error| Fault::new(error).with_operands(&[&left, &right]);
+        match promote(left.clone(), right.clone())? {
+            Numbers::Integers(l, r) => {
+                if r.is_zero() { return Err(blame(Error::DivisionByZero)) }
+                self.push_rational(BigRational::new(l, r))
             },
-            (Data::Float(l), Data::Float(r)) => {
-                self.push_float(l - r)?
+            Numbers::Rationals(l, r) => {
+                if r.is_zero() { return Err(blame(Error::DivisionByZero)) }
+                self.push_rational(l / r)
             },
-            _ => return Err(Error::TypeError),
+            Numbers::Floats(l, r) => self.push_float(l / r),
         }
         Ok(())
     }
 
-    fn prod(&mut self) -> Result<(), Error> {
-        let (left, right) = self.pop_two();
-        match (left, right) {
-            (Data::Integer(l), Data::Integer(r)) => {
-                self.push_integer(l * r)?
+    fn rem(&mut self) -> Result<(), Fault> {
+        let (left, right) = self.pop_two()?;
+        let blame = |error| Fault::new(error).with_operands(&[&left, &right]);
+        match promote(left.clone(), right.clone())? {
+            Numbers::Integers(l, r) => {
+                if r.is_zero() { return Err(blame(Error::DivisionByZero)) }
+                self.push_integer(l % r)
             },
-            (Data::Float(l), Data::Float(r)) => {
-                self.push_float(l * r)?
+            Numbers::Rationals(l, r) => {
+                if r.is_zero() { return Err(blame(Error::DivisionByZero)) }
+                self.push_rational(l % r)
             },
-            _ => return Err(Error::TypeError),
+            Numbers::Floats(l, r) => self.push_float(l % r),
         }
         Ok(())
     }
 
-    fn div(&mut self) -> Result<(), Error> {
-        let (left, right) = self.pop_two();
-        match (left, right) {
-            (Data::Integer(l), Data::Integer(r)) => {
-                self.push_integer(l * r)?
+    /// Exact numbers to integer powers stay exact, the rest are floats
+    fn pow(&mut self) -> Result<(), Fault> {
+        let (left, right) = self.pop_two()?;
+        let blame = |error| Fault::new(error).with_operands(&[&left, &right]);
+        match promote(left.clone(), right.clone())? {
+            Numbers::Integers(l, r) => {
+                let r = r.to_i32().ok_or_else(|| blame(Error::Overflow))?;
+                if l.is_zero() && r < 0 { return Err(blame(Error::DivisionByZero)) }
+                self.push_rational(BigRational::from_integer(l).pow(r))
             },
-            (Data::Float(l), Data::Float(r)) => {
-                self.push_float(l * r)?
+            Numbers::Rationals(l, r) => {
+                if r.is_integer() {
+                    let r = r.to_integer().to_i32().ok_or_else(|| blame(Error::Overflow))?;
+                    if l.is_zero() && r < 0 { return Err(blame(Error::DivisionByZero)) }
+                    self.push_rational(l.pow(r))
+                } else {
+                    self.push_float(to_float(&Data::Rational(l)).powf(to_float(&Data::Rational(r))))
+                }
             },
-            _ => return Err(Error::TypeError),
+            Numbers::Floats(l, r) => self.push_float(l.powf(r)),
         }
         Ok(())
     }
 
-    fn swap(&mut self) -> Result<(), !> {
-        let (l, r) = self.pop_two();
+    /// Apply `op` to the number on the top
+    fn unary<F>(&mut self, op: F) -> Result<(), Fault>
+        where F: FnOnce(Data) -> Result<Data, Error>
+    {
+        let val = self.pop_one()?;
+        if !val.is_number() {
+            return Err(Fault::new(Error::TypeError).with_operands(&[&val]))
+        }
+        let result = op(val.clone()).map_err(|error| Fault::new(error).with_operands(&[&val]))?;
+        self.stack.push(result);
+        Ok(())
+    }
+
+    fn floor(&mut self) -> Result<(), Fault> {
+        self.unary(|n| match n {
+            Data::Rational(r) => Ok(Data::Integer(r.floor().to_integer())),
+            Data::Float(f) => float_to_integer(f.floor()),
+            n => Ok(n),
+        })
+    }
+
+    fn ceil(&mut self) -> Result<(), Fault> {
+        self.unary(|n| match n {
+            Data::Rational(r) => Ok(Data::Integer(r.ceil().to_integer())),
+            Data::Float(f) => float_to_integer(f.ceil()),
+            n => Ok(n),
+        })
+    }
+
+    /// Round to the nearest integer, halfway away from zero
+    fn round(&mut self) -> Result<(), Fault> {
+        self.unary(|n| match n {
+            Data::Rational(r) => Ok(Data::Integer(r.round().to_integer())),
+            Data::Float(f) => float_to_integer(f.round()),
+            n => Ok(n),
+        })
+    }
+
+    /// Round towards zero
+    fn trunc(&mut self) -> Result<(), Fault> {
+        self.unary(|n| match n {
+            Data::Rational(r) => Ok(Data::Integer(r.trunc().to_integer())),
+            Data::Float(f) => float_to_integer(f.trunc()),
+            n => Ok(n),
+        })
+    }
+
+    fn float(&mut self) -> Result<(), Fault> {
+        self.unary(|n| Ok(Data::Float(to_float(&n))))
+    }
+
+    fn negate(&mut self) -> Result<(), Fault> {
+        self.unary(|n| match n {
+            Data::Integer(i) => Ok(Data::Integer(-i)),
+            Data::Rational(r) => Ok(Data::Rational(-r)),
+            Data::Float(f) => Ok(Data::Float(-f)),
+            n => Ok(n),
+        })
+    }
+
+    fn abs(&mut self) -> Result<(), Fault> {
+        self.unary(|n| match n {
+            Data::Integer(i) => Ok(Data::Integer(i.abs())),
+            Data::Rational(r) => Ok(Data::Rational(r.abs())),
+            Data::Float(f) => Ok(Data::Float(f.abs())),
+            n => Ok(n),
+        })
+    }
+
+    fn swap(&mut self) -> Result<(), Fault> {
+        let (l, r) = self.pop_two()?;
         self.stack.push(r);
         self.stack.push(l);
         Ok(())
     }
 
-    fn dup(&mut self) -> Result<(), !> {
-        let v = self.pop_one();
+    fn dup(&mut self) -> Result<(), Fault> {
+        let v = self.pop_one()?;
         self.stack.push(v.clone());
         self.stack.push(v);
         Ok(())
     }
 
-    fn drop(&mut self) -> Result<(), !> {
-        drop(self.pop_one());
+    fn drop(&mut self) -> Result<(), Fault> {
+        drop(self.pop_one()?);
         Ok(())
     }
 
-    fn comma(&mut self) -> Result<(), Error> {
-        let (list, val) = self.pop_two();
+    fn comma(&mut self) -> Result<(), Fault> {
+        let (list, val) = self.pop_two()?;
         match (list, val) {
             (Data::List(mut vs), v) => {
                 vs.push_back(v);
                 self.stack.push(Data::List(vs));
             },
-            _ => return Err(Error::TypeError),
+            (l, r) => return Err(Fault::new(Error::TypeError).with_operands(&[&l, &r])),
         }
         Ok(())
     }
 
-    fn behead(&mut self) -> Result<(), Error> {
-        let list = self.pop_one();
+    fn behead(&mut self) -> Result<(), Fault> {
+        let list = self.pop_one()?;
         match list {
             Data::List(mut vs) => {
-                let v = vs.pop_front().ok_or(Error::ListError)?;
+                let v = match vs.pop_front() {
+                    Some(v) => v,
+                    None => return Err(Fault::new(Error::ListError).with_operands(&[&Data::List(vs)])),
+                };
                 self.stack.push(v);
                 self.stack.push(Data::List(vs));
             },
-            _ => return Err(Error::TypeError),
+            l => return Err(Fault::new(Error::TypeError).with_operands(&[&l])),
         }
         Ok(())
     }
 
-    fn print(&mut self) -> Result<(), Error> {
-        let val = self.pop_one();
-        match val {
-            Data::Integer(i) => print!("{}", i),
-            Data::Float(f) => print!("{}", f),
-            Data::Bool(b) => print!("{}", b),
-            Data::String(s) => print!("{}", s),
-            _ => return Err(Error::PrintError),
+    /// `⍙`: like `⍘`, from the other end
+    fn curtail(&mut self) -> Result<(), Fault> {
+        let mut vs = self.pop_list()?;
+        let v = match vs.pop_back() {
+            Some(v) => v,
+            None => return Err(Fault::new(Error::ListError).with_operands(&[&Data::List(vs)])),
+        };
+        self.stack.push(v);
+        self.stack.push(Data::List(vs));
+        Ok(())
+    }
+
+    fn pop_list(&mut self) -> Result<VecDeque<Data>, Fault> {
+        match self.pop_one()? {
+            Data::List(vs) => Ok(vs),
+            l => Err(Fault::new(Error::TypeError).with_operands(&[&l])),
         }
+    }
+
+    /// A list, and a count of its elements on top of it that must not be more than it has
+    fn pop_list_and_count(&mut self) -> Result<(VecDeque<Data>, usize), Fault> {
+        match self.pop_two()? {
+            (Data::List(vs), Data::Integer(n)) => match n.to_usize() {
+                Some(count) if count <= vs.len() => Ok((vs, count)),
+                _ => Err(Fault::new(Error::ListError).with_operands(&[&Data::List(vs), &Data::Integer(n)])),
+            },
+            (l, r) => Err(Fault::new(Error::TypeError).with_operands(&[&l, &r])),
+        }
+    }
+
+    fn length(&mut self) -> Result<(), Fault> {
+        let vs = self.pop_list()?;
+        self.push_integer(vs.len().into());
         Ok(())
     }
 
-    fn execute_word(&mut self, word: Word) -> Result<(), Error> {
-        use self::Word::*;
-        match word {
-            Gt => self.greater_than()?,
-            Eq => self.equals()?,
-            Lt => self.lesser_than()?,
-            Plus => self.plus()?,
-            Minus => self.minus()?,
-            Prod => self.prod()?,
-            Div => self.div()?,
-            Swap => self.swap()?,
-            Dup => self.dup()?,
-            Drop => self.drop()?,
-            Id => (), // `id` is no-op
-            Zilde => self.push_zilde()?,
-            Comma => self.comma()?,
-            Behead => self.behead()?,
-            Print => self.print()?,
-            Rec => self.recurse()?,
-        }
-        Ok(())
-    }
-
-    fn recurse(&mut self) -> Result<(), Error> {
-        let (init, quote) = self.pop_two();
-        match (init, quote) {
-            (v, Data::Quotation(q)) => {
-                if q.arity() != Arity(1, 2) { return Err(Error::ArityError) }
+    /// `⌷`: the element at a 0-based index
+    fn index(&mut self) -> Result<(), Fault> {
+        match self.pop_two()? {
+            (Data::List(vs), Data::Integer(i)) => match i.to_usize().and_then(|i| vs.get(i).cloned()) {
+                Some(v) => self.stack.push(v),
+                None => return Err(Fault::new(Error::ListError).with_operands(&[&Data::List(vs), &Data::Integer(i)])),
+            },
+            (l, r) => return Err(Fault::new(Error::TypeError).with_operands(&[&l, &r])),
+        }
+        Ok(())
+    }
 
-                self.stack.push(v);
-                loop {
-                    self.execute(&*q)?;
-                    let cond = self.pop_one();
-                    match cond {
-                        Data::Bool(false) => break,
-                        Data::Bool(true) => (),
-                        _ => return Err(Error::TypeError),
-                    }
-                }
+    fn catenate(&mut self) -> Result<(), Fault> {
+        match self.pop_two()? {
+            (Data::List(mut l), Data::List(mut r)) => {
+                l.append(&mut r);
+                self.stack.push(Data::List(l));
             },
-            _ => return Err(Error::TypeError),
+            (l, r) => return Err(Fault::new(Error::TypeError).with_operands(&[&l, &r])),
         }
         Ok(())
     }
 
-    pub fn execute(&mut self, expr: &Arited) -> Result<(), Error> {
-        use self::Arited::*;
+    fn reverse(&mut self) -> Result<(), Fault> {
+        let vs = self.pop_list()?;
+        self.stack.push(Data::List(vs.into_iter().rev().collect()));
+        Ok(())
+    }
 
-        match expr {
-            &Composition(ref comp, _) => {
-                for e in comp {
-                    self.execute(&e)?
-                }
+    fn take(&mut self) -> Result<(), Fault> {
+        let (mut vs, n) = self.pop_list_and_count()?;
+        vs.truncate(n);
+        self.stack.push(Data::List(vs));
+        Ok(())
+    }
+
+    fn skip(&mut self) -> Result<(), Fault> {
+        let (mut vs, n) = self.pop_list_and_count()?;
+        vs.drain(..n);
+        self.stack.push(Data::List(vs));
+        Ok(())
+    }
+
+    /// `∊`: whether the value on the top is equal to some element of the list under it
+    fn member(&mut self) -> Result<(), Fault> {
+        match self.pop_two()? {
+            (Data::List(vs), v) => self.push_bool(vs.iter().any(|e| equal(e, &v))),
+            (l, r) => return Err(Fault::new(Error::TypeError).with_operands(&[&l, &r])),
+        }
+        Ok(())
+    }
+
+    fn print(&mut self) -> Result<(), Fault> {
+        let val = self.pop_one()?;
+        write!(self.output, "{}", val.show()).map_err(|e| Error::IoError(e.kind()))?;
+        Ok(())
+    }
+
+    fn show(&mut self) -> Result<(), Fault> {
+        let val = self.pop_one()?;
+        self.push_string(val.show());
+        Ok(())
+    }
+
+    /// A line without its line break, or `None` at the end of input
+    fn read_line(&mut self) -> Result<Option<String>, Fault> {
+        let mut line = String::new();
+        let read = self.input.read_line(&mut line).map_err(|e| Error::IoError(e.kind()))?;
+        if read == 0 { return Ok(None) }
+
+        if line.ends_with('\n') { line.pop(); }
+        if line.ends_with('\r') { line.pop(); }
+        Ok(Some(line))
+    }
+
+    fn read_line_word(&mut self) -> Result<(), Fault> {
+        match self.read_line()? {
+            Some(line) => {
+                self.push_string(line);
+                self.push_bool(true);
             },
-            &Concatenation(ref conc, arity) => {
-                self.retain(arity.0 as usize);
-                for e in conc {
-                    self.restore(e.arity().0 as usize);
-                    self.execute(&e)?
-                }
+            None => {
+                self.push_string(String::new());
+                self.push_bool(false);
             },
-            &Question(ref cons, ref alter, _) => {
-                let cond = self.pop_one();
-                match cond {
-                    Data::Bool(true) => self.execute(cons)?,
-                    Data::Bool(false) => self.execute(alter)?,
-                    _ => return Err(Error::TypeError),
-                }
+        }
+        Ok(())
+    }
+
+    fn read_all(&mut self) -> Result<(), Fault> {
+        let mut all = String::new();
+        self.input.read_to_string(&mut all).map_err(|e| Error::IoError(e.kind()))?;
+        self.push_string(all);
+        Ok(())
+    }
+
+    fn read_number(&mut self) -> Result<(), Fault> {
+        let line = match self.read_line()? {
+            Some(line) => line,
+            None => {
+                self.push_integer(BigInt::zero());
+                self.push_bool(false);
+                return Ok(())
             },
-            &Word(w, _) => self.execute_word(w)?,
-            &Integer(i) => self.push_integer(i)?,
-            &Float(f) => self.push_float(f)?,
-            &String(ref s) => self.push_string(s.clone())?,
-            &Quotation(ref q) => self.push_quotation(q.clone())?,
-            &IdN(_) => (),
+        };
+
+        let number = line.trim();
+        if let Ok(i) = number.parse() {
+            self.push_integer(i);
+        } else if let Ok(f) = number.parse() {
+            self.push_float(f);
+        } else {
+            return Err(Fault::new(Error::InputError).with_operands(&[&Data::String(line)]))
         }
+        self.push_bool(true);
         Ok(())
     }
 
-    pub fn execute_program(&mut self, expr: &Arited) -> Result<(), Error> {
-        if expr.arity().0 != 0 { return Err(Error::ArityError) }
+    /// Run `word`, which was given `arity` when the program was checked
+    fn execute_word(&mut self, word: Word, arity: Arity) -> Result<(), Fault> {
+        use self::Word::*;
+        match word {
+            Gt => self.greater_than(),
+            Eq => self.equals(),
+            NotEq => self.not_equals(),
+            Lt => self.lesser_than(),
+            True => { self.push_bool(true); Ok(()) },
+            False => { self.push_bool(false); Ok(()) },
+            And => self.logic(|l, r| l && r),
+            Or => self.logic(|l, r| l || r),
+            Xor => self.logic(|l, r| l != r),
+            Not => self.not(),
+            Plus => self.plus(),
+            Minus => self.minus(),
+            Prod => self.prod(),
+            Div => self.div(),
+            Rem => self.rem(),
+            Pow => self.pow(),
+            Floor => self.floor(),
+            Ceil => self.ceil(),
+            Round => self.round(),
+            Trunc => self.trunc(),
+            ToFloat => self.float(),
+            Negate => self.negate(),
+            Abs => self.abs(),
+            Swap => self.swap(),
+            Dup => self.dup(),
+            Drop => self.drop(),
+            Id => Ok(()), // `id` is no-op
+            Zilde => { self.push_zilde(); Ok(()) },
+            Comma => self.comma(),
+            Behead => self.behead(),
+            Curtail => self.curtail(),
+            Length => self.length(),
+            Index => self.index(),
+            Catenate => self.catenate(),
+            Reverse => self.reverse(),
+            Take => self.take(),
+            Skip => self.skip(),
+            Member => self.member(),
+            Print => self.print(),
+            Show => self.show(),
+            ReadLine => self.read_line_word(),
+            ReadAll => self.read_all(),
+            ReadNumber => self.read_number(),
+            Rec => self.recurse(arity),
+            Apply => self.apply(),
+            Times => self.times(arity),
+            While => self.while_loop(arity),
+        }
+    }
+
+    fn pop_quotation(&mut self) -> Result<Box<Arited>, Fault> {
+        match self.pop_one()? {
+            Data::Quotation(q) => Ok(q),
+            v => Err(Fault::new(Error::TypeError).with_operands(&[&v])),
+        }
+    }
+
+    /// The Boolean a loop condition left on the top
+    fn pop_condition(&mut self) -> Result<bool, Fault> {
+        match self.pop_one()? {
+            Data::Bool(b) => Ok(b),
+            c => Err(Fault::new(Error::TypeError).with_operands(&[&c])),
+        }
+    }
+
+    /// `∇`: run an `n → n+1` quotation on the `n` values under it for as long as it leaves true on the top
+    ///
+    /// Where the quotation could not be seen before the run, `∇` was given an arity for some `n`,
+    /// and only a quotation of that `n` keeps a concatenation around it from taking the wrong values.
+    fn recurse(&mut self, arity: Arity) -> Result<(), Fault> {
+        let q = self.pop_quotation()?;
+        let Arity(carried, left) = q.arity();
+        if left != carried + 1 || arity != Arity(carried + 1, carried) {
+            return Err(Fault::new(Error::ArityError).with_operands(&[&Data::Quotation(q)]))
+        }
+
+        self.ensure(carried as usize)?;
+        loop {
+            self.execute(&q)?;
+            if !self.pop_condition()? { break }
+        }
+        Ok(())
+    }
+
+    /// `⍣`: run an `n → n` quotation a number of times, none if the number is negative
+    fn times(&mut self, arity: Arity) -> Result<(), Fault> {
+        let (q, count) = match self.pop_two()? {
+            (Data::Quotation(q), Data::Integer(count)) => (q, count),
+            (l, r) => return Err(Fault::new(Error::TypeError).with_operands(&[&l, &r])),
+        };
+        let Arity(carried, left) = q.arity();
+        if left != carried || arity != Arity(carried + 2, carried) {
+            return Err(Fault::new(Error::ArityError).with_operands(&[&Data::Quotation(q)]))
+        }
+
+        let count = if count.is_negative() {
+            0
+        } else {
+            count.to_u64().ok_or_else(|| {
+                Fault::new(Error::Overflow).with_operands(&[&Data::Quotation(q.clone()), &Data::Integer(count.clone())])
+            })?
+        };
+        self.ensure(carried as usize)?;
+        for _ in 0..count {
+            self.execute(&q)?;
+        }
+        Ok(())
+    }
+
+    /// `⍤`: run an `n → n` body for as long as an `m → m+1` condition leaves true on the top
+    fn while_loop(&mut self, arity: Arity) -> Result<(), Fault> {
+        let (cond, body) = match self.pop_two()? {
+            (Data::Quotation(cond), Data::Quotation(body)) => (cond, body),
+            (l, r) => return Err(Fault::new(Error::TypeError).with_operands(&[&l, &r])),
+        };
+        let (Arity(c_in, c_out), Arity(b_in, b_out)) = (cond.arity(), body.arity());
+        let carried = c_in.max(b_in);
+        if c_out != c_in + 1 || b_out != b_in || arity != Arity(carried + 2, carried) {
+            let (cond, body) = (Data::Quotation(cond), Data::Quotation(body));
+            return Err(Fault::new(Error::ArityError).with_operands(&[&cond, &body]))
+        }
+
+        self.ensure(carried as usize)?;
+        loop {
+            self.execute(&cond)?;
+            if !self.pop_condition()? { break }
+            self.execute(&body)?;
+        }
+        Ok(())
+    }
+
+    /// Run the quotation on the top on what is under it
+    fn apply(&mut self) -> Result<(), Fault> {
+        let q = self.pop_quotation()?;
+        self.ensure(q.arity().0 as usize)?;
+        self.nested(&q)
+    }
+
+    fn composition(&mut self, comp: &[Arited]) -> Result<(), Fault> {
+        for e in comp {
+            self.execute(e)?
+        }
+        Ok(())
+    }
+
+    /// `;`: run each part on its own share of the arguments
+    fn concatenation(&mut self, conc: &[Arited], arity: Arity, span: Span) -> Result<(), Fault> {
+        self.retain(arity.0 as usize)
+            .map_err(|e| Fault::new(e).raised_by(Culprit::Concatenation, span, &self.stack))?;
+        for e in conc {
+            self.restore(e.arity().0 as usize)
+                .map_err(|e| Fault::new(e).raised_by(Culprit::Concatenation, span, &self.stack))?;
+            self.execute(e)?
+        }
+        Ok(())
+    }
+
+    fn question(&mut self, cons: &Arited, alter: &Arited, span: Span) -> Result<(), Fault> {
+        let cond = self.pop_one()
+            .map_err(|e| Fault::new(e).raised_by(Culprit::Question, span, &self.stack))?;
+        match cond {
+            Data::Bool(true) => self.execute(cons),
+            Data::Bool(false) => self.execute(alter),
+            c => {
+                let fault = Fault::new(Error::TypeError).with_operands(&[&c]);
+                Err(fault.raised_by(Culprit::Question, span, &self.stack))
+            },
+        }
+    }
+
+    fn word(&mut self, w: Word, arity: Arity, span: Span) -> Result<(), Fault> {
+        self.execute_word(w, arity)
+            .map_err(|fault| fault.raised_by(Culprit::Word(w), span, &self.stack))
+    }
+
+    fn call(&mut self, name: &str, span: Span) -> Result<(), Fault> {
+        let body = match self.words.get(name) {
+            Some(body) => body.clone(),
+            None => {
+                let fault = Fault::new(Error::UnknownWord);
+                return Err(fault.raised_by(Culprit::Call(name.to_string()), span, &self.stack))
+            },
+        };
+        self.nested(&body)
+            .map_err(|fault| fault.raised_by(Culprit::Call(name.to_string()), span, &self.stack))
+    }
+
+    /// Run a called body, one level deeper
+    fn nested(&mut self, body: &Arited) -> Result<(), Fault> {
+        if self.depth == MAX_DEPTH {
+            return Err(Error::TooDeep.into())
+        }
+        self.depth += 1;
+        let result = self.execute(body);
+        self.depth -= 1;
+        result
+    }
+
+    fn definitions(&mut self, defs: &[Definition<Arited>], main: &Arited) -> Result<(), Fault> {
+        for d in defs {
+            self.words.insert(d.name.clone(), Rc::new(d.body.clone()));
+        }
+        self.execute(main)
+    }
+
+    /// Run `expr` on whatever is on the stack already
+    pub fn execute(&mut self, expr: &Arited) -> Result<(), Fault> {
+        use self::Arited::*;
+
+        match *expr {
+            Composition(ref comp, _, _) => self.composition(comp),
+            Concatenation(ref conc, arity, span) => self.concatenation(conc, arity, span),
+            Question(ref cons, ref alter, _, span) => self.question(cons, alter, span),
+            Word(w, arity, span) => self.word(w, arity, span),
+            Integer(ref i, _) => { self.push_integer(i.clone()); Ok(()) },
+            Rational(ref r, _) => { self.push_rational(r.clone()); Ok(()) },
+            Float(f, _) => { self.push_float(f); Ok(()) },
+            String(ref s, _) => { self.push_string(s.clone()); Ok(()) },
+            Quotation(ref q, _) => { self.push_quotation(q.clone()); Ok(()) },
+            IdN(_, _) => Ok(()),
+            Call(ref name, _, span) => self.call(name, span),
+            Definitions(ref defs, ref main, _) => self.definitions(defs, main),
+        }
+    }
+
+    pub fn execute_program(&mut self, expr: &Arited) -> Result<(), Fault> {
+        if expr.arity().0 != 0 {
+            return Err(Fault::new(Error::ArityError).raised_by(Culprit::Program, expr.span(), &self.stack))
+        }
         self.execute(expr)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::parser;
+    use super::super::ast::{Arited, Arity, Span, Word};
+    use num_rational::BigRational;
+    use std::thread;
+
+    use super::{Culprit, Data, Error, Fault, Machine};
+
+    fn run(code: &str) -> Result<(), Fault> {
+        let ast = Arited::from_expression(parser::parse(code).unwrap()).unwrap();
+        Machine::new().execute_program(&ast)
+    }
+
+    #[test] fn type_error_names_the_word() {
+        let fault = run("7 1 'one' +").unwrap_err();
+        assert_eq!(fault.error, Error::TypeError);
+        assert_eq!(fault.culprit, Some(Culprit::Word(Word::Plus)));
+        assert_eq!(fault.operands, vec!["Integer", "String"]);
+        assert_eq!(fault.stack, vec!["7", "1", "'one'"]);
+        assert_eq!(fault.span.map(|s| s.column), Some(11));
+    }
+
+    #[test] fn arithmetic_errors_keep_their_operands() {
+        let fault = run("'x' ⎕ 1 0 ÷").unwrap_err();
+        assert_eq!(fault.error, Error::DivisionByZero);
+        assert_eq!(fault.operands, vec!["Integer", "Integer"]);
+        assert_eq!(fault.stack, vec!["1", "0"]);
+        let fault = run("10.0 1000.0 * ⌊").unwrap_err();
+        assert_eq!(fault.error, Error::Overflow);
+        assert_eq!(fault.operands, vec!["Float"]);
+    }
+
+    #[test] fn deep_recursion_faults() {
+        // A thousand calls need more native stack in a debug build than test threads get
+        let deep = |code: String| {
+            thread::Builder::new().stack_size(64 << 20).spawn(move || run(&code)).unwrap().join().unwrap()
+        };
+        let fault = deep("f 1 → 1 ← f ⋄ 1 f".to_string()).unwrap_err();
+        assert_eq!(fault.error, Error::TooDeep);
+        assert_eq!(fault.culprit, Some(Culprit::Call("f".to_string())));
+        let fact = "fact 1 → 1 ← ⇈ 1 > (?⇈ 1 − fact ×:↓ 1. ·) ⋄ ";
+        assert!(deep(format!("{}900 fact", fact)).is_ok());
+        assert_eq!(deep(format!("{}5000 fact", fact)).unwrap_err().error, Error::TooDeep);
+    }
+
+    #[test] fn innermost_culprit_wins() {
+        let fault = run("0 [↓⍬⍘] ∇").unwrap_err();
+        assert_eq!(fault.error, Error::ListError);
+        assert_eq!(fault.culprit, Some(Culprit::Word(Word::Behead)));
+    }
+
+    #[test] fn question_condition() {
+        let fault = run("1 (?2:3.·)").unwrap_err();
+        assert_eq!(fault.culprit, Some(Culprit::Question));
+        assert_eq!(fault.operands, vec!["Integer"]);
+    }
+
+    #[test] fn underflow_is_caught() {
+        let ast = Arited::from_expression(parser::parse("1 ↓ ↓").unwrap()).unwrap();
+        let fault = Machine::new().execute(&ast).unwrap_err();
+        assert_eq!(fault.error, Error::StackUnderflow { needed: 1, available: 0 });
+        assert_eq!(fault.culprit, Some(Culprit::Word(Word::Drop)));
+        assert_eq!(
+            fault.to_string().lines().next(),
+            Some("stack underflow: needed 1, but only 0 available in `↓` at 1:5")
+        );
+    }
+
+    #[test] fn output_is_captured() {
+        assert_eq!(output("'a' ⎕ 1 ⎕ 1.5 ⎕", ""), Ok("a11.5".to_string()));
+    }
+
+    #[test] fn input_words() {
+        assert_eq!(output("⍈↓⎕ ⍞↓⎕ ⍞⎕↓ ⍈⎕⎕", " 12\r\nfoo\n"), Ok("12foo⊥⊥0".to_string()));
+        assert_eq!(output("⍈", "twelve\n").unwrap_err().error, Error::InputError);
+        assert_eq!(output("⍞↓↓ ⍇⎕", "skip\nrest\nof it"), Ok("rest\nof it".to_string()));
+    }
+
+    fn top(code: &str) -> Result<Data, Fault> {
+        let ast = Arited::from_expression(parser::parse(code).unwrap()).unwrap();
+        let mut machine = Machine::new();
+        machine.execute_program(&ast)?;
+        Ok(machine.pop().unwrap())
+    }
+
+    /// What running `code` as a program prints, given `input`
+    fn output(code: &str, input: &str) -> Result<String, Fault> {
+        let ast = Arited::from_expression(parser::parse(code).unwrap()).unwrap();
+        let mut out = vec![];
+        Machine::with_io(input.as_bytes(), &mut out).execute_program(&ast)?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[test] fn arithmetic() {
+        assert_eq!(top("8 2 ÷"), Ok(Data::Integer(4.into())));
+        assert_eq!(top("7.0 2.0 ÷"), Ok(Data::Float(3.5)));
+        assert_eq!(top("7 2 |"), Ok(Data::Integer(1.into())));
+        assert_eq!(top("2 10 *"), Ok(Data::Integer(1024.into())));
+        assert_eq!(top("2 0 1 − *"), Ok(Data::Rational(ratio(1, 2))));
+        assert_eq!(top("2.5 ⌊"), Ok(Data::Integer(2.into())));
+        assert_eq!(top("2.5 ⌈"), Ok(Data::Integer(3.into())));
+        assert_eq!(top("3 ±"), Ok(Data::Integer((-3).into())));
+        assert_eq!(top("0 3 − ‖"), Ok(Data::Integer(3.into())));
+        assert_eq!(top("1.5 ± ‖"), Ok(Data::Float(1.5)));
+    }
+
+    #[test] fn arithmetic_errors() {
+        assert_eq!(top("1 0 ÷").unwrap_err().error, Error::DivisionByZero);
+        assert_eq!(top("1 0 |").unwrap_err().error, Error::DivisionByZero);
+        assert_eq!(top("1 0 ÷ ⊙").unwrap_err().error, Error::DivisionByZero);
+        assert_eq!(top("0 0 1 − *").unwrap_err().error, Error::DivisionByZero);
+        assert_eq!(top("2 10000000000 *").unwrap_err().error, Error::Overflow);
+        assert_eq!(top("10.0 1000.0 * ⌊").unwrap_err().error, Error::Overflow);
+    }
+
+    #[test] fn numeric_promotion() {
+        // Integers meet floats as floats
+        assert_eq!(top("2 1.5 +"), Ok(Data::Float(3.5)));
+        assert_eq!(top("1.5 2 ×"), Ok(Data::Float(3.0)));
+        assert_eq!(top("3 2.0 ÷"), Ok(Data::Float(1.5)));
+        assert_eq!(top("2 0.5 *"), Ok(Data::Float(2f64.sqrt())));
+        assert_eq!(top("2 2.0 ="), Ok(Data::Bool(true)));
+        assert_eq!(top("2 2.5 <"), Ok(Data::Bool(true)));
+        // Integers among themselves stay exact
+        assert_eq!(top("7 2 ÷"), Ok(Data::Rational(ratio(7, 2))));
+        // But not if asked not to
+        assert_eq!(top("7 ⊙ 2 ÷"), Ok(Data::Float(3.5)));
+        assert_eq!(top("1 'a' +").unwrap_err().operands, vec!["Integer", "String"]);
+    }
+
+    fn ratio(n: i64, d: i64) -> BigRational {
+        BigRational::new(n.into(), d.into())
+    }
+
+    #[test] fn big_integers() {
+        assert_eq!(
+            top("9223372036854775807 1 +"),
+            Ok(Data::Integer("9223372036854775808".parse().unwrap()))
+        );
+        assert_eq!(
+            top("2 100 *"),
+            Ok(Data::Integer("1267650600228229401496703205376".parse().unwrap()))
+        );
+        assert_eq!(top("2 100 * 2 99 * ÷"), Ok(Data::Integer(2.into())));
+        assert_eq!(output("99999999999999999999 1 + ⎕", ""), Ok("100000000000000000000".to_string()));
+    }
+
+    #[test] fn rationals() {
+        assert_eq!(top("3r4"), Ok(Data::Rational(ratio(3, 4))));
+        assert_eq!(top("6r3"), Ok(Data::Integer(2.into())));
+        assert_eq!(top("1 3 ÷ 1 6 ÷ +"), Ok(Data::Rational(ratio(1, 2))));
+        assert_eq!(top("1 3 ÷ 3 ×"), Ok(Data::Integer(1.into())));
+        assert_eq!(top("1r2 0.25 +"), Ok(Data::Float(0.75)));
+        assert_eq!(top("7r2 ⌊"), Ok(Data::Integer(3.into())));
+        assert_eq!(top("7r2 2 |"), Ok(Data::Rational(ratio(3, 2))));
+        assert_eq!(top("1 3 ÷ 1 2 ÷ <"), Ok(Data::Bool(true)));
+        assert_eq!(top("2 4 ÷ 1r2 ="), Ok(Data::Bool(true)));
+        assert_eq!(output("3 4 ÷ ⎕", ""), Ok("3r4".to_string()));
+    }
+
+    #[test] fn logic() {
+        assert_eq!(top("⊤"), Ok(Data::Bool(true)));
+        assert_eq!(top("⊤⊥∧"), Ok(Data::Bool(false)));
+        assert_eq!(top("⊤⊥∨"), Ok(Data::Bool(true)));
+        assert_eq!(top("⊤⊤⊻"), Ok(Data::Bool(false)));
+        assert_eq!(top("⊥~"), Ok(Data::Bool(true)));
+        assert_eq!(top("1 2 < 3 2 < ∨ ~"), Ok(Data::Bool(false)));
+        assert_eq!(top("⊤ ~ (?1:2.·)"), Ok(Data::Integer(2.into())));
+        let fault = top("⊤ 1 ∧").unwrap_err();
+        assert_eq!(fault.error, Error::TypeError);
+        assert_eq!(fault.operands, vec!["Bool", "Integer"]);
+        assert_eq!(top("0 ~").unwrap_err().culprit, Some(Culprit::Word(Word::Not)));
+    }
+
+    #[test] fn equality() {
+        assert_eq!(top("'a' 'a' ="), Ok(Data::Bool(true)));
+        assert_eq!(top("'a' 'b' ≠"), Ok(Data::Bool(true)));
+        assert_eq!(top("⊤ ⊤ ="), Ok(Data::Bool(true)));
+        assert_eq!(top("⍬1,'x', ⍬1.0,'x', ="), Ok(Data::Bool(true)));
+        assert_eq!(top("⍬1,2, ⍬1, ="), Ok(Data::Bool(false)));
+        assert_eq!(top("1r2 0.5 ="), Ok(Data::Bool(true)));
+        assert_eq!(top("[1 +] [1 +] ="), Ok(Data::Bool(true)));
+        // Different types are just different
+        assert_eq!(top("1 '1' ="), Ok(Data::Bool(false)));
+        assert_eq!(top("⍬ ⊥ ≠"), Ok(Data::Bool(true)));
+        assert_eq!(top("0.0 0.0 ÷ ⇈ ≠"), Ok(Data::Bool(true)));
+    }
+
+    #[test] fn ordering() {
+        assert_eq!(top("'abc' 'abd' <"), Ok(Data::Bool(true)));
+        assert_eq!(top("'b' 'abc' >"), Ok(Data::Bool(true)));
+        assert_eq!(top("⍬1,2, ⍬1,2,3, <"), Ok(Data::Bool(true)));
+        assert_eq!(top("⍬1,3, ⍬1,2,3, >"), Ok(Data::Bool(true)));
+        assert_eq!(top("⍬'a', ⍬'a', <"), Ok(Data::Bool(false)));
+        assert_eq!(top("⊥ ⊤ <"), Ok(Data::Bool(true)));
+        assert_eq!(top("1r3 0.3 >"), Ok(Data::Bool(true)));
+        let fault = top("1 '1' <").unwrap_err();
+        assert_eq!(fault.error, Error::TypeError);
+        assert_eq!(fault.culprit, Some(Culprit::Word(Word::Lt)));
+        assert_eq!(top("⍬1, ⍬'a', >").unwrap_err().operands, vec!["Integer", "String"]);
+        assert_eq!(top("[·] [·] <").unwrap_err().error, Error::TypeError);
+    }
+
+    #[test] fn showing() {
+        let shown = |code: &str| match top(&format!("{} ⍕", code)) {
+            Ok(Data::String(s)) => s,
+            v => panic!("Not shown: {:?}", v),
+        };
+        assert_eq!(shown("0 3 −"), "¯3");
+        assert_eq!(shown("3 4 ÷ ±"), "¯3r4");
+        assert_eq!(shown("3 ⊙"), "3.0");
+        assert_eq!(shown("⊤"), "⊤");
+        assert_eq!(shown("'it\\'s'"), "it's");
+        assert_eq!(shown("⍬"), "⍬");
+        assert_eq!(shown("⍬1,'a\\n',⍬2.5,,⊥,"), "⍬1,'a\\n',⍬2.5,,⊥,");
+        assert_eq!(shown("[1`+`]"), "[1;· +]");
+        // Even the floats there are no literals for read back
+        assert_eq!(shown("1e308 10 ×"), "(1.0 0.0 ÷)");
+        assert_eq!(shown("⍬1e308 ± 10 ×,"), "⍬(¯1.0 0.0 ÷),");
+        assert_eq!(shown("0.0 0.0 ÷"), "(0.0 0.0 ÷)");
+        assert_eq!(shown(&shown("1e308 10 ×")), "(1.0 0.0 ÷)");
+
+        assert_eq!(output("⍬1,2, ⎕ [⇈ ×] ⎕ '\\'' ⎕", ""), Ok("⍬1,2,[⇈ ×]'".to_string()));
+    }
+
+    #[test] fn faults_abbreviate_the_stack() {
+        let fault = top("⍬1,2,3,4,5,6,7,8,9,10,11,12, 1 '1' <").unwrap_err();
+        assert_eq!(fault.stack, vec!["⍬1,2,3,4,5,6,7,8,9,10,11,12,", "1", "'1'"]);
+        let fault = top("⍬1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20, 1 '1' <").unwrap_err();
+        assert_eq!(fault.stack[0], "⍬1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16…");
+    }
+
+    #[test] fn apply() {
+        assert_eq!(top("2 [3 +] ⍎"), Ok(Data::Integer(5.into())));
+        assert_eq!(top("4 [⇈ ×] ⍎"), Ok(Data::Integer(16.into())));
+        assert_eq!(top("1 [[1 +] ⍎] ⍎"), Ok(Data::Integer(2.into())));
+        assert_eq!(top("[1] [2] ⊥ (?·;↓:↓;·. ⍎)"), Ok(Data::Integer(2.into())));
+        let ast = Arited::from_expression(parser::parse("1 [+] ⍎").unwrap()).unwrap();
+        let fault = Machine::new().execute(&ast).unwrap_err();
+        assert_eq!(fault.error, Error::StackUnderflow { needed: 2, available: 1 });
+        assert_eq!(fault.culprit, Some(Culprit::Word(Word::Apply)));
+        let fault = top("1 2 ⍎").unwrap_err();
+        assert_eq!(fault.operands, vec!["Integer"]);
+        assert_eq!(top("[1 '1' <] ⍎").unwrap_err().culprit, Some(Culprit::Word(Word::Lt)));
+    }
+
+    #[test] fn loops() {
+        // Sum of 1..10, with the sum and the counter both carried through the loop
+        assert_eq!(top("0 10 [⇈ +;(1 −) ⇈ 0 >] ∇ ↓"), Ok(Data::Integer(55.into())));
+        // Quotations the checker cannot follow are checked when they run
+        assert_eq!(top("1 ·;[⍞↓⍞] ∇ ↓").unwrap_err().error, Error::ArityError);
+        let fault = top("0 ·;[1 ⊤] ∇").unwrap_err();
+        assert_eq!(fault.error, Error::ArityError);
+        assert_eq!(fault.culprit, Some(Culprit::Word(Word::Rec)));
+
+        assert_eq!(top("1 [2 ×] 10 ⍣"), Ok(Data::Integer(1024.into())));
+        assert_eq!(top("1 [2 ×] 0 ⍣"), Ok(Data::Integer(1.into())));
+        assert_eq!(top("1 [2 ×] ¯3 ⍣"), Ok(Data::Integer(1.into())));
+        assert_eq!(top("0 1 [⇈;⇈ ↓;↔;· +] 10 ⍣ ↔ ↓"), Ok(Data::Integer(89.into())));
+        assert_eq!(top("1 ·;[↓] 2 ⍣").unwrap_err().error, Error::ArityError);
+        // Quotations that could not be seen before the run must fit the arity their combinator was given
+        assert_eq!(top("1 ·;[2 ×] 2 ⍣").unwrap_err().error, Error::ArityError);
+        assert_eq!(top("0 10 ·;[⇈ +;(1 −) ⇈ 0 >] ∇").unwrap_err().error, Error::ArityError);
+        assert_eq!(top("1 ·;[⇈ 100 <] [2 ×] ⍤").unwrap_err().error, Error::ArityError);
+        assert_eq!(top("5 ·;[1 − ⇈ 0 >] ∇"), Ok(Data::Integer(0.into())));
+        assert_eq!(top("1 [2 ×] 1.5 ⍣").unwrap_err().operands, vec!["Quotation", "Float"]);
+        let ast = Arited::from_expression(parser::parse("[2 ×] 2 ⍣").unwrap()).unwrap();
+        let fault = Machine::new().execute(&ast).unwrap_err();
+        assert_eq!(fault.error, Error::StackUnderflow { needed: 1, available: 0 });
+        assert_eq!(fault.culprit, Some(Culprit::Word(Word::Times)));
+
+        assert_eq!(top("1 [⇈ 100 <] [2 ×] ⍤"), Ok(Data::Integer(128.into())));
+        assert_eq!(top("1 [⊥] [1 +] ⍤"), Ok(Data::Integer(1.into())));
+        assert_eq!(top("1 ·;[1 <] [2 ×] ⍤").unwrap_err().error, Error::ArityError);
+        assert_eq!(top("1 [⇈ 1 <] ·;[2] ⍤").unwrap_err().error, Error::ArityError);
+        let fault = top("1 [⇈] [·] ⍤").unwrap_err();
+        assert_eq!(fault.error, Error::TypeError);
+        assert_eq!(fault.culprit, Some(Culprit::Word(Word::While)));
+    }
+
+    #[test] fn rounding() {
+        assert_eq!(top("2.5 ⊚"), Ok(Data::Integer(3.into())));
+        assert_eq!(top("2.5 ± ⊚"), Ok(Data::Integer((-3).into())));
+        assert_eq!(top("2.4 ⊚"), Ok(Data::Integer(2.into())));
+        assert_eq!(top("2.7 ⊘"), Ok(Data::Integer(2.into())));
+        assert_eq!(top("2.7 ± ⊘"), Ok(Data::Integer((-2).into())));
+        assert_eq!(top("2.7 ± ⌊"), Ok(Data::Integer((-3).into())));
+        assert_eq!(top("7 ⊘"), Ok(Data::Integer(7.into())));
+    }
+
+    #[test] fn definitions() {
+        assert_eq!(top("fact 1 → 1 ← ⇈ 1 > (?⇈ 1 − fact ×:↓ 1. ·) ⋄ 20 fact"), Ok(Data::Integer(2432902008176640000u64.into())));
+        assert_eq!(top("sq ← ⇈ × ⋄ quad ← sq sq ⋄ 3 quad"), Ok(Data::Integer(81.into())));
+        assert_eq!(top("inc ← 1 + ⋄ 1 [inc inc] ⍎"), Ok(Data::Integer(3.into())));
+        assert_eq!(top("drop ← ↓ ⋄ ⍬ ⍘ drop").unwrap_err().culprit, Some(Culprit::Word(Word::Behead)));
+
+        // Definitions outlive the program, and calls to words that never were are caught
+        let mut machine = Machine::new();
+        let defs = Arited::from_expression(parser::parse("inc ← 1 + ⋄").unwrap()).unwrap();
+        machine.execute(&defs).unwrap();
+        machine.push(Data::Integer(1.into()));
+        machine.execute(&Arited::Call("inc".into(), Arity(1, 1), Span::default())).unwrap();
+        assert_eq!(machine.pop(), Some(Data::Integer(2.into())));
+        let fault = machine.execute(&Arited::Call("dec".into(), Arity(1, 1), Span::default())).unwrap_err();
+        assert_eq!(fault.error, Error::UnknownWord);
+        assert_eq!(fault.culprit, Some(Culprit::Call("dec".into())));
+    }
+
+    #[test] fn list_words() {
+        let list = |code: &str| top(code).map(|v| v.to_string());
+        assert_eq!(list("⍬1,2,3, ≢"), Ok("3".to_string()));
+        assert_eq!(list("⍬1,2,3, 0 ⌷"), Ok("1".to_string()));
+        assert_eq!(list("⍬1,2,3, 2 ⌷"), Ok("3".to_string()));
+        assert_eq!(list("⍬1,2, ⍬3, ⍪"), Ok("⍬1,2,3,".to_string()));
+        assert_eq!(list("⍬1,2,3, ⌽"), Ok("⍬3,2,1,".to_string()));
+        assert_eq!(list("⍬1,2,3, 2 ↑"), Ok("⍬1,2,".to_string()));
+        assert_eq!(list("⍬1,2,3, 2 ⇣"), Ok("⍬3,".to_string()));
+        assert_eq!(list("⍬1,2,3, 3 ⇣"), Ok("⍬".to_string()));
+        assert_eq!(list("⍬1,2,3, ⍙ ↓"), Ok("3".to_string()));
+        assert_eq!(list("⍬1,2,3, ⍙"), Ok("⍬1,2,".to_string()));
+        assert_eq!(list("⍬1,2,3, 2.0 ∊"), Ok("⊤".to_string()));
+        assert_eq!(list("⍬'a', 'b' ∊"), Ok("⊥".to_string()));
+
+        for code in &["⍬1,2,3, 3 ⌷", "⍬1, 0 1 − ⌷", "⍬1,2,3, 4 ↑", "⍬1, 2 ⇣", "⍬ ⍙"] {
+            assert_eq!(top(code).unwrap_err().error, Error::ListError, "{}", code);
+        }
+        let fault = top("⍬1,2,3, 3 ⌷").unwrap_err();
+        assert_eq!(fault.culprit, Some(Culprit::Word(Word::Index)));
+        assert_eq!(fault.operands, vec!["List", "Integer"]);
+    }
+}
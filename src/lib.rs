@@ -0,0 +1,165 @@
+//! Esobsc is an implementation of [␣;](https://suhr.github.io/obsc/) for the esolang community.
+//!
+//! Running a program takes three steps: parse the source, check its arity and types, run it on a `Machine`.
+//!
+//! ```
+//! use esobsc::{Machine, Value};
+//!
+//! let mut machine = Machine::new();
+//! machine.push(Value::Integer(2.into()));
+//! esobsc::run("3 ×", &mut machine).unwrap();
+//! assert_eq!(machine.pop(), Some(Value::Integer(6.into())));
+//! ```
+
+// A `Fault` carries its culprit, the operands and a snapshot of the stack. It is only ever built on
+// the way out of a failing run, so there is nothing to gain from boxing it.
+#![allow(clippy::result_large_err)]
+
+extern crate num_bigint;
+extern crate num_rational;
+extern crate num_traits;
+
+pub mod ast;
+pub mod parser;
+pub mod types;
+pub mod eval;
+pub mod module;
+pub mod prelude;
+
+use std::fmt;
+use std::path::Path;
+
+pub use num_bigint::BigInt;
+pub use num_rational::BigRational;
+
+pub use ast::{Arited, Arity, ArityError, Definition, DefinitionError, Expression, Import, QuotationMismatch, Span};
+pub use eval::{Data as Value, Fault, Machine};
+pub use module::{ImportError, ModuleError};
+pub use parser::ParseError;
+pub use types::{Effect, Type, TypeError};
+
+/// Anything that can go wrong between source code and the end of a run
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    Parse(ParseError),
+    Arity(ArityError),
+    Type(TypeError),
+    Runtime(Fault),
+    Import(ImportError),
+    /// An error in an imported file
+    Module(Box<ModuleError>),
+}
+
+impl Error {
+    /// A human-readable diagnostic, with the offending parts of `source` underlined
+    pub fn report(&self, source: &str) -> String {
+        match *self {
+            Error::Parse(ref e) => format!("syntax error: {}", e),
+            Error::Arity(ref e) => {
+                let branches = e.mismatches.iter()
+                    .map(|m| format!("arity error: {}\n{}", m, m.question.excerpt(source)));
+                let quotations = e.quotations.iter()
+                    .map(|m| format!("arity error: {}\n{}", m, m.quotation.1.excerpt(source)));
+                let definitions = e.definitions.iter()
+                    .map(|m| format!("arity error: {}\n{}", m, m.span().excerpt(source)));
+                let reports: Vec<String> = branches.chain(quotations).chain(definitions).collect();
+                reports.join("\n")
+            },
+            Error::Type(ref e) => format!("type error: {}\n{}", e, e.span.excerpt(source)),
+            Error::Import(ref e) => format!("import error: {}\n{}", e, e.span.excerpt(source)),
+            Error::Module(ref e) => format!("in `{}`:\n{}", e.path.display(), e.error.report(&e.source)),
+            Error::Runtime(ref e) => match e.span {
+                Some(span) => format!("error: {}\n{}", e, span.excerpt(source)),
+                None => format!("error: {}", e),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Parse(ref e) => write!(f, "{}", e),
+            Error::Arity(ref e) => write!(f, "{}", e),
+            Error::Type(ref e) => write!(f, "{}", e),
+            Error::Runtime(ref e) => write!(f, "{}", e),
+            Error::Import(ref e) => write!(f, "{}", e),
+            Error::Module(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Error {
+        Error::Parse(e)
+    }
+}
+
+impl From<ArityError> for Error {
+    fn from(e: ArityError) -> Error {
+        Error::Arity(e)
+    }
+}
+
+impl From<TypeError> for Error {
+    fn from(e: TypeError) -> Error {
+        Error::Type(e)
+    }
+}
+
+impl From<ImportError> for Error {
+    fn from(e: ImportError) -> Error {
+        Error::Import(e)
+    }
+}
+
+impl From<Fault> for Error {
+    fn from(e: Fault) -> Error {
+        Error::Runtime(e)
+    }
+}
+
+/// Parse source code into a syntax tree
+pub fn parse(source: &str) -> Result<Expression, ParseError> {
+    parser::parse(source)
+}
+
+/// Infer arities and types of a syntax tree, making it ready to run on a stack
+///
+/// The words of the prelude come along, in front of it, like they do for the command line.
+pub fn check(expr: Expression) -> Result<Arited, Error> {
+    module::with_prelude(expr, types::check)
+}
+
+/// Like `check`, for a complete program, which must not expect anything on the stack
+pub fn check_program(expr: Expression) -> Result<Arited, Error> {
+    module::with_prelude(expr, types::check_program)
+}
+
+/// Parse and check `source` together with the files it imports, relative to `file` it came from,
+/// and with the prelude in front of it unless `prelude` is false
+pub fn load(source: &str, file: Option<&Path>, prelude: bool) -> Result<Arited, Error> {
+    module::load(source, file, prelude)
+}
+
+/// Parse, check and run `source` on whatever is on the machine's stack already, with the prelude
+pub fn run(source: &str, machine: &mut Machine<'_>) -> Result<(), Error> {
+    let program = check(parse(source)?)?;
+    machine.execute(&program)?;
+    Ok(())
+}
+
+/// Parse, check and run `source` as a complete program on a fresh machine, with the prelude
+///
+/// ```
+/// use esobsc::Value;
+///
+/// let machine = esobsc::run_program("1 2 over").unwrap();
+/// assert_eq!(machine.stack(), &[Value::Integer(1.into()), Value::Integer(2.into()), Value::Integer(1.into())]);
+/// ```
+pub fn run_program(source: &str) -> Result<Machine<'static>, Error> {
+    let program = check_program(parse(source)?)?;
+    let mut machine = Machine::new();
+    machine.execute_program(&program)?;
+    Ok(machine)
+}
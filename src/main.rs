@@ -1,32 +1,41 @@
-#![feature(never_type)]
-
-#![feature(plugin)]
-#![plugin(oak)]
-
-#[macro_use] extern crate lazy_static;
-extern crate oak_runtime;
-extern crate regex;
-
-mod ast;
-mod parser;
-mod eval;
+extern crate esobsc;
 
 use std::io::{Read, stdin};
 use std::env::args;
 use std::fs::File;
+use std::path::PathBuf;
+use std::process::exit;
 
 fn main() {
     let mut code = String::new();
-    match args().nth(1) {
-        Some(fname) => {
-            File::open(fname).unwrap()
-                .read_to_string(&mut code).unwrap();
-        },
-        None => {
-            stdin().read_to_string(&mut code).unwrap();
-        },
+    let (flags, files): (Vec<String>, Vec<String>) = args().skip(1).partition(|a| a.starts_with("--"));
+    if let Some(flag) = flags.iter().find(|f| *f != "--no-prelude") {
+        eprintln!("error: unknown option `{}`\nusage: esobsc [--no-prelude] [file]", flag);
+        exit(1);
+    }
+    if files.len() > 1 {
+        eprintln!("error: more than one file to run\nusage: esobsc [--no-prelude] [file]");
+        exit(1);
+    }
+    let prelude = flags.is_empty();
+    let file = files.into_iter().next().map(PathBuf::from);
+    let read = match file {
+        Some(ref fname) => File::open(fname).and_then(|mut f| f.read_to_string(&mut code)),
+        None => stdin().read_to_string(&mut code),
+    };
+    if let Err(error) = read {
+        match file {
+            Some(ref fname) => eprintln!("error: cannot read `{}`: {}", fname.display(), error),
+            None => eprintln!("error: cannot read the standard input: {}", error),
+        }
+        exit(1);
+    }
+    let result = match esobsc::load(&code, file.as_deref(), prelude) {
+        Ok(program) => esobsc::Machine::new().execute_program(&program).map_err(esobsc::Error::from),
+        Err(error) => Err(error),
     };
-    let syntax = parser::parse(code).unwrap();
-    let ast = ast::Arited::from_expression(syntax);
-    eval::Machine::new().execute_program(&ast).unwrap();
+    if let Err(error) = result {
+        eprintln!("{}", error.report(&code));
+        exit(1);
+    }
 }
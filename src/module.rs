@@ -0,0 +1,286 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::ast::{Arited, ArityError, Definition, DefinitionError, Expression, Span};
+use super::types::{self, Effect, TypeError};
+use super::{parser, prelude, Error};
+
+// Files import files, which get checked on their own before the importing one sees them.
+// What an importing file gets is their definitions under its names for them:
+//
+//     list ⇐ 'lib/list.eso' ⋄
+//     ⍬ 1, 2, list::sum
+//
+// All of it ends up as one big list of definitions in front of the program that runs.
+
+/// An import that could not be followed
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportError {
+    /// The import in the importing file
+    pub span: Span,
+    pub path: PathBuf,
+    pub reason: Reason,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reason {
+    Unreadable(io::ErrorKind),
+    /// Files that import each other, from `path` back to it
+    Cycle(Vec<PathBuf>),
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.reason {
+            Reason::Unreadable(kind) => write!(f, "cannot read `{}`: {}", self.path.display(), io::Error::from(kind))?,
+            Reason::Cycle(ref cycle) => {
+                let chain: Vec<String> = cycle.iter().map(|p| p.display().to_string()).collect();
+                write!(f, "`{}` imports itself: {}", self.path.display(), chain.join(" → "))?
+            },
+        }
+        write!(f, " at {}:{}", self.span.line, self.span.column)
+    }
+}
+
+/// Something wrong in an imported file, with the file to show it in
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModuleError {
+    pub path: PathBuf,
+    pub source: String,
+    pub error: Error,
+}
+
+impl fmt::Display for ModuleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "in `{}`: {}", self.path.display(), self.error)
+    }
+}
+
+/// Files being loaded, each as it is found and as it was named
+type Loading = Vec<(PathBuf, PathBuf)>;
+
+/// Parse and check `source` with everything it imports, and the prelude if `prelude` says so
+///
+/// Imports are relative to the directory of `file`, or to the current one if there is none.
+/// Imported files get the prelude too. `source` is a complete program, which must not expect
+/// anything on the stack, while whatever the imported files would do besides defining words
+/// is only checked for what it does to the stack.
+pub fn load(source: &str, file: Option<&Path>, prelude: bool) -> Result<Arited, Error> {
+    let mut loading = vec![];
+    if let Some(file) = file {
+        if let Ok(found) = fs::canonicalize(file) {
+            loading.push((found, file.to_path_buf()));
+        }
+    }
+    let dir = file.and_then(Path::parent).unwrap_or_else(|| Path::new(""));
+    check(source, dir, prelude, &mut loading, types::check_program)
+}
+
+pub(crate) type Checker = fn(&Arited) -> Result<Effect, TypeError>;
+
+/// Check `expr` with the prelude in front of it, like `load` does with source that imports nothing
+pub(crate) fn with_prelude(expr: Expression, typed: Checker) -> Result<Arited, Error> {
+    let prelude = prelude_for(&expr, Span::new(0, 0, ""));
+    checked(expr, prelude, typed)
+}
+
+/// The words of the prelude, with the ones `expr` defines for itself hidden
+fn prelude_for(expr: &Expression, span: Span) -> Vec<Definition<Arited>> {
+    let own: Vec<String> = match *expr {
+        Expression::Definitions(ref defs, _, _) => defs.iter().map(|d| d.name.clone()).collect(),
+        _ => vec![],
+    };
+    prelude::definitions(span, &own)
+}
+
+fn check(source: &str, dir: &Path, prelude: bool, loading: &mut Loading, typed: Checker) -> Result<Arited, Error> {
+    let (imports, expr) = parser::parse_module(source)?;
+
+    let mut imported: Vec<Definition<Arited>> = vec![];
+    if prelude {
+        imported = prelude_for(&expr, Span::new(0, 0, source));
+    }
+    for (i, import) in imports.iter().enumerate() {
+        if imports[..i].iter().any(|other| other.name == import.name) {
+            let duplicate = DefinitionError::Duplicate(import.name.clone(), import.span);
+            return Err(ArityError { mismatches: vec![], quotations: vec![], definitions: vec![duplicate] }.into())
+        }
+
+        let path = dir.join(&import.path);
+        let fail = |reason| ImportError { span: import.span, path: path.clone(), reason };
+        let found = fs::canonicalize(&path).map_err(|e| fail(Reason::Unreadable(e.kind())))?;
+        if let Some(at) = loading.iter().position(|(f, _)| *f == found) {
+            let mut cycle: Vec<PathBuf> = loading[at..].iter().map(|(_, named)| named.clone()).collect();
+            cycle.push(path.clone());
+            return Err(fail(Reason::Cycle(cycle)).into())
+        }
+        let code = fs::read_to_string(&found).map_err(|e| fail(Reason::Unreadable(e.kind())))?;
+
+        loading.push((found, path.clone()));
+        let module = check(&code, path.parent().unwrap_or_else(|| Path::new("")), prelude, loading, types::check)
+            .map_err(|error| Error::Module(Box::new(ModuleError { path: path.clone(), source: code.clone(), error })))?;
+        loading.pop();
+
+        // Whatever the imported file does besides defining words is its own business
+        if let Arited::Definitions(defs, _, _) = module {
+            imported.extend(defs.into_iter().map(|d| d.imported(Some(&import.name), import.span)));
+        }
+    }
+
+    checked(expr, imported, typed)
+}
+
+/// Infer arities and types of `expr`, with the `imported` words it calls in front of it
+fn checked(expr: Expression, mut imported: Vec<Definition<Arited>>, typed: Checker) -> Result<Arited, Error> {
+    let program = match Arited::from_expression_in(expr, &imported)? {
+        Arited::Definitions(defs, main, span) => {
+            imported.extend(defs);
+            Arited::Definitions(imported, main, span)
+        },
+        main if !imported.is_empty() => {
+            let span = main.span();
+            Arited::Definitions(imported, Box::new(main), span)
+        },
+        main => main,
+    };
+    typed(&program)?;
+    Ok(program)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::ops::Deref;
+    use std::path::{Path, PathBuf};
+
+    use super::super::eval::{Data, Machine};
+    use super::super::Error;
+    use super::{load, Reason};
+
+    /// A directory of its own for a test, gone with everything in it once the test is done
+    struct Dir(PathBuf);
+
+    impl Deref for Dir {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for Dir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// A directory of its own for every test, with `files` in it
+    fn files(test: &str, files: &[(&str, &str)]) -> Dir {
+        let dir = Dir(env::temp_dir().join(format!("esobsc-{}-{}", test, std::process::id())));
+        for &(name, code) in files {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, code).unwrap();
+        }
+        dir
+    }
+
+    fn run(dir: &Path, main: &str) -> Result<Data, Error> {
+        let file = dir.join(main);
+        let program = load(&fs::read_to_string(&file).unwrap(), Some(&file), false)?;
+        let mut machine = Machine::new();
+        machine.execute_program(&program)?;
+        Ok(machine.pop().unwrap())
+    }
+
+    #[test] fn imports() {
+        let dir = files("imports", &[
+            ("main.eso", "l ⇐ 'lib/list.eso' ⋄ twice ← l::double l::double ⋄ 1 twice l::n::inc"),
+            ("lib/list.eso", "n ⇐ 'num.eso' ⋄ double ← 2 × ⋄ 'not run' ⎕"),
+            ("lib/num.eso", "inc ← 1 + ⋄"),
+        ]);
+        assert_eq!(run(&dir, "main.eso"), Ok(Data::Integer(5.into())));
+    }
+
+    #[test] fn quotations_across_files() {
+        let dir = files("quotations", &[
+            ("main.eso", "l ⇐ 'lib.eso' ⋄ 0 10 l::sumq ∇ ↓ 0 10 l::again ∇ ↓ +"),
+            ("lib.eso", "sumq ← [⇈ +;(1 −) ⇈ 0 >] ⋄ again ← sumq ⋄"),
+        ]);
+        assert_eq!(run(&dir, "main.eso"), Ok(Data::Integer(110.into())));
+    }
+
+    #[test] fn preludes_of_their_own() {
+        let dir = files("preludes", &[
+            ("main.eso", "l ⇐ 'lib.eso' ⋄ 1 2 3 l::tuck l::over"),
+            ("lib.eso", "over ← ↓ ⋄"),
+        ]);
+        let file = dir.join("main.eso");
+        let program = load(&fs::read_to_string(&file).unwrap(), Some(&file), true).unwrap();
+        let mut machine = Machine::new();
+        machine.execute_program(&program).unwrap();
+        let stack: Vec<String> = machine.stack().iter().map(Data::to_string).collect();
+        assert_eq!(stack, vec!["1", "3", "2"]);
+    }
+
+    #[test] fn checked_across_files() {
+        let dir = files("checked", &[
+            ("lib.eso", "inc ← 1 + ⋄"),
+            ("types.eso", "l ⇐ 'lib.eso' ⋄ 'a' l::inc"),
+            ("arity.eso", "l ⇐ 'lib.eso' ⋄ l::dec"),
+            ("twice.eso", "l ⇐ 'lib.eso' ⋄ l ⇐ 'lib.eso' ⋄ 1"),
+        ]);
+        match run(&dir, "types.eso") {
+            Err(Error::Type(e)) => assert_eq!((e.expected.as_str(), e.found.as_str(), e.span.column), ("Number", "String", 21)),
+            r => panic!("Not a type error: {:?}", r),
+        }
+        match run(&dir, "arity.eso") {
+            Err(Error::Arity(e)) => assert_eq!(e.to_string(), "`l::dec` at 1:17 is not defined"),
+            r => panic!("Not an arity error: {:?}", r),
+        }
+        match run(&dir, "twice.eso") {
+            Err(Error::Arity(e)) => assert_eq!(e.to_string(), "`l` at 1:17 is already defined"),
+            r => panic!("Not an arity error: {:?}", r),
+        }
+    }
+
+    #[test] fn errors_name_the_file() {
+        let dir = files("errors", &[
+            ("main.eso", "b ⇐ 'broken.eso' ⋄ 1"),
+            ("broken.eso", "x ← 1 ⋄\ny ← (2 ⋄"),
+            ("missing.eso", "m ⇐ 'nowhere.eso' ⋄ 1"),
+        ]);
+        let error = run(&dir, "main.eso").unwrap_err();
+        let source = fs::read_to_string(dir.join("main.eso")).unwrap();
+        let report = error.report(&source);
+        assert!(report.starts_with(&format!("in `{}`:\nsyntax error:", dir.join("broken.eso").display())));
+        assert!(report.contains("2 | y ← (2 ⋄"));
+
+        match run(&dir, "missing.eso") {
+            Err(Error::Import(e)) => assert!(matches!(e.reason, Reason::Unreadable(_))),
+            r => panic!("Not an import error: {:?}", r),
+        }
+    }
+
+    #[test] fn cycles() {
+        let dir = files("cycles", &[
+            ("a.eso", "b ⇐ 'b.eso' ⋄ 1"),
+            ("b.eso", "a ⇐ 'a.eso' ⋄"),
+            ("self.eso", "s ⇐ 'self.eso' ⋄ 1"),
+        ]);
+        match run(&dir, "a.eso") {
+            Err(Error::Module(e)) => match e.error {
+                Error::Import(ref e) => match e.reason {
+                    Reason::Cycle(ref cycle) => assert_eq!(cycle, &[dir.join("a.eso"), dir.join("b.eso"), dir.join("a.eso")]),
+                    ref r => panic!("Not a cycle: {:?}", r),
+                },
+                ref e => panic!("Not an import error: {:?}", e),
+            },
+            r => panic!("Not an error in b.eso: {:?}", r),
+        }
+        assert!(matches!(run(&dir, "self.eso"), Err(Error::Import(_))));
+    }
+}
@@ -1,231 +1,695 @@
-#![allow(dead_code)]
-use oak_runtime::*;
-use regex::Regex;
+use std::collections::HashMap;
+use std::fmt;
+
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::Zero;
+
+use super::ast::{Arity, Definition, Expression, Import, Span, Word};
+
+const WORDS: &[Word] = &[
+    Word::Gt, Word::Eq, Word::NotEq, Word::Lt,
+    Word::True, Word::False, Word::And, Word::Or, Word::Xor, Word::Not,
+    Word::Plus, Word::Minus, Word::Prod, Word::Div,
+    Word::Rem, Word::Pow, Word::Negate, Word::Abs,
+    Word::Floor, Word::Ceil, Word::Round, Word::Trunc, Word::ToFloat,
+    Word::Swap, Word::Dup, Word::Drop, Word::Id,
+    Word::Zilde, Word::Comma, Word::Behead, Word::Curtail,
+    Word::Length, Word::Index, Word::Catenate, Word::Reverse, Word::Take, Word::Skip, Word::Member,
+    Word::Print, Word::Show, Word::ReadLine, Word::ReadAll, Word::ReadNumber,
+    Word::Rec, Word::Apply, Word::Times, Word::While,
+];
+
+/// Prefixes of integers that are not decimal, and what has to follow them
+const RADICES: &[(&str, u32, &str)] = &[
+    ("0x", 16, "a hexadecimal digit"),
+    ("0o", 8, "an octal digit"),
+    ("0b", 2, "a binary digit"),
+];
+
+/// The most values a definition can declare to take or leave
+const MAX_COUNT: u32 = 255;
+
+/// A number as it is written, before it gets its sign and its span
+enum Number {
+    Integer(BigInt),
+    Rational(BigRational),
+    Float(f64),
+}
 
-use super::ast::*;
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
 
-lazy_static! {
-    static ref NEWLINE_REGEX: Regex = Regex::new(r"[^\\](\\n)").unwrap();
-    static ref SLASH_REGEX: Regex = Regex::new(r"(\\\\)").unwrap();
+/// Decimal digits, maybe with underscores, that are already known to be there
+fn decimal_integer(digits: &str) -> BigInt {
+    digits.replace('_', "").parse().unwrap()
 }
 
 // Parsing is scary. Very scary.
 // Be afraid!
+//
+// The grammar, as a PEG. Every token eats the spacing after it.
+//
+//     program = spacing (import / definition)* expression !.    // a program is, but, an expression. And some names
+//     import = name "⇐" string "⋄"    // only where there is a file to import from
+//     definition = name (count "→" count)? "←" expression "⋄"
+//
+//     // Damn left recursion
+//     expression = composition / concatenation / non_empty / plain / ε
+//     non_empty = infix / concatenation / ensquared / enclosed
+//     plain = string / number / word / call
+//
+//     // This will parse ``a `b` `c` d`` as ``(a `b`) (`c` d)``. It actually should not, but it does.
+//     infix = simple (infixed simple)+ / simple infixed / infixed simple
+//     // A greater flexibility is lost to make parsing simplier
+//     infixed = "`" (word / call) "`"
+//
+//     concatenation = simple (";" simple)+
+//     composition = (infix / concatenation / simple) (non_empty / plain)+
+//     question = "?" expression ":" expression "."
+//
+//     // Simple as ``(⍬`,`·`,`·`,`·)``
+//     simple = question / ensquared / enclosed / string / number / word / call
+//     enclosed = "(" expression ")"
+//     ensquared = "[" expression "]"
+//
+//     // Numbers got fancier. Still not like in your lovely javascript
+//     number = "¯"? (radix / decimal) !namechar
+//     radix = "0x" digits(16) / "0o" digits(8) / "0b" digits(2)
+//     decimal = digits(10) "r" digits(10) / digits(10) ("." digits(10))? ([eE] "¯"? digits(10))?
+//     digits(n) = digit(n)+ ("_" digit(n)+)*
+//     string = "'" (escape / !"'" .)* "'"
+//     escape = "\\" ([ntr0'\\] / "u{" hexdigit+ "}")
+//     call = name ("::" name)*
+//     name = letter namechar*    // letters as Unicode has them, so no APL symbols
+//     namechar = letter / digit / "_"
+//     count = digits(10)
+//
+//     // Sweet APL symbols... Too bad, fonts you use suck.
+//     // This is how they actually should look like: http://aplwiki.com/AplCharacters
+//     spacing = ([ \n\r\t] / "⍝" (!"\n" .)*)*
+
+/// I have no lexer and I must parse
+struct Parser<'a> {
+    code: &'a str,
+    pos: usize,
+    /// Where the last token ended, not counting the spacing after it
+    last_end: usize,
+    /// The furthest point where something was expected, and what was
+    furthest: usize,
+    expected: Vec<&'static str>,
+    /// `simple` gets tried over and over at the same place, so remember how it went
+    simples: HashMap<usize, Option<(Expression, usize, usize)>>,
+    /// Whether the code comes from a file that other files can be imported relative to
+    imports: bool,
+}
+
+impl<'a> Parser<'a> {
+    fn new(code: &'a str) -> Self {
+        Parser {
+            code,
+            pos: 0,
+            last_end: 0,
+            furthest: 0,
+            expected: vec![],
+            simples: HashMap::new(),
+            imports: false,
+        }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.code[self.pos..]
+    }
 
-grammar! obsc {
-    program = spacing expression    // a program is, but, an expression
-
-    // Damn left recursion
-    expression
-        = composition
-        / concatenation
-        / non_empty
-        / plain
-        / spacing > empty_expression
-
-    non_empty
-        = infix
-        / concatenation
-        / ensquared
-        / enclosed
-
-    plain
-        = string
-        / number
-        / word > word_expression
-
-    // This will parse ``a `b` `c` d`` as ``(a `b`) (`c` d)``. It actually should not, but it does.
-    infix
-        = simple (infixed simple)+ > infix_chain
-        / simple infixed > infix_left
-        / infixed simple > infix_right
-    // A greater flexibility is lost to make parsing simplier
-    infixed = gravis word gravis
-
-    concatenation = simple (concat simple)+ > concatenation
-    composition = (infix / concatenation / simple) (non_empty / plain)+ > composition
-    question = qmark expression colon expression dot > question
-
-    // Simple as ``(⍬`,`·`,`·`,`·)``
-    simple
-        = question
-        / ensquared
-        / enclosed
-        / string
-        / number
-        / word > word_expression
-
-    enclosed
-        = lparren expression rparren > enclosed_expression
-
-    ensquared
-        = lbracket expression rbracket > quotation_expression
-
-    word
-        = gt > gt_word
-        / eq > eq_word
-        / lt > lt_word
-        / plus > plus_word
-        / minus > minus_word
-        / prod > prod_word
-        / div > div_word
-        / swap > swap_word
-        / dup > dup_word
-        / drop > drop_word
-        / id > id_word
-        / zilde > zilde_word
-        / comma > comma_word
-        / behead > behead_word
-        / print > print_word
-        / rec > rec_word
-
-    // Only simplest shapes of numbers. Not like in your lovely javascript
-    number
-        = digit+ "." digit+ spacing > float_expression
-        / digit+ spacing > integer_expression
-    digit = ["0-9"]
-
-
-    string = "'" (!eos .)* "'" spacing > string_expression
-    eos = !"\\" "'"
-
-    // “Grave accent”? Sorry, no burial here.
-    // Why don't you just call it “groove”?
-    gravis = "`" spacing -> ()
-
-    // Sweet APL symbols... Too bad, fonts you use suck.
-    // This is how they actually should look like: http://aplwiki.com/AplCharacters
-    comment = "⍝" (!"\n" .)* -> (^)
-
-    // I have no lexer and I must parse
-    lparren = "(" spacing -> ()
-    rparren = ")" spacing -> ()
-    lbracket = "[" spacing -> ()
-    rbracket = "]" spacing -> ()
-
-    // Behold the unicode
-    qmark = "?" spacing -> ()
-    colon = ":" spacing -> ()
-    dot = "." spacing -> ()
-
-    rec = "∇" spacing -> ()
-    print = "⎕" spacing -> ()
-    concat = ";" spacing -> ()
-
-    zilde = "⍬" spacing -> ()
-    comma = "," spacing -> ()
-    behead = "⍘" spacing -> () // I have no idea what this symbol is for in APL
-
-    swap = "↔" spacing -> ()
-    dup = "⇈" spacing -> ()
-    drop = "↓" spacing -> ()
-    id = "·" spacing -> ()
-
-    gt = ">" spacing -> ()
-    eq = "=" spacing -> ()
-    lt = "<" spacing -> ()
-    plus = "+" spacing -> ()
-    minus = "−" spacing -> ()
-    prod = "×" spacing -> ()
-    div = "÷" spacing -> ()
-
-    // Defining things in reverse
-    spacing = [" \n\r\t"]* comment* -> (^)
-
-    // Super code use
-    use super::*;
-
-    // Hello, boilerplate!
-    fn gt_word() -> Word { Word::Gt }
-    fn eq_word() -> Word { Word::Eq }
-    fn lt_word() -> Word { Word::Lt }
-    fn plus_word() -> Word { Word::Plus }
-    fn minus_word() -> Word { Word::Minus }
-    fn prod_word() -> Word { Word::Prod }
-    fn div_word() -> Word { Word::Div }
-
-    fn swap_word() -> Word { Word::Swap }
-    fn dup_word() -> Word { Word::Dup }
-    fn drop_word() -> Word { Word::Drop }
-    fn id_word() -> Word { Word::Id }
-
-    fn zilde_word() -> Word { Word::Zilde }
-    fn comma_word() -> Word { Word::Comma }
-    fn behead_word() -> Word { Word::Behead }
-
-    fn print_word() -> Word { Word::Print }
-    fn rec_word() -> Word { Word::Rec }
-
-    fn word_expression(w: Word) -> Expression { Expression::Word(w) }
-    fn float_expression(left: Vec<char>, right: Vec<char>) -> Expression {
-        use std::iter::once;
-        let float: String =
-            left.into_iter().chain(once('.')).chain(right.into_iter()).collect();
-        Expression::Float(float.parse().unwrap())
-    }
-    fn integer_expression(int: Vec<char>) -> Expression {
-        let int: String = int.into_iter().collect();
-        Expression::Integer(int.parse().unwrap())
-    }
-    fn string_expression(s: Vec<char>) -> Expression {
-        let string: String = s.into_iter().collect();
-        let string = NEWLINE_REGEX.replace_all(&string, "\n");
-        let string = SLASH_REGEX.replace_all(&string, "\\");
-        Expression::String(string.into_owned())
-    }
-    fn quotation_expression(q: Expression) -> Expression { Expression::Quotation(Box::new(q)) }
-    fn enclosed_expression(e: Expression) -> Expression { e }
-    fn empty_expression() -> Expression { Expression::Nop }
-
-    fn infix_chain(exp: Expression, chain: Vec<(Word, Expression)>) -> Expression {
-        let mut chain = chain.into_iter();
-        let init = {
-            let (w, e) = chain.next().unwrap();
-            Expression::Composition(vec![
-                Expression::Concatenation(vec![exp, e]),
-                Expression::Word(w)
-            ])
+    fn span_from(&self, start: usize) -> Span {
+        Span::new(start, self.last_end, self.code)
+    }
+
+    fn expect(&mut self, what: &'static str) {
+        let pos = self.pos;
+        self.expect_at(pos, what)
+    }
+
+    fn expect_at(&mut self, pos: usize, what: &'static str) {
+        if pos > self.furthest {
+            self.furthest = pos;
+            self.expected.clear();
+        }
+        if pos == self.furthest {
+            self.expected.push(what);
+        }
+    }
+
+    /// Run `rule`, and if it fails, pretend it was never run
+    fn attempt<T, F>(&mut self, rule: F) -> Option<T>
+        where F: FnOnce(&mut Self) -> Option<T>
+    {
+        let (pos, last_end) = (self.pos, self.last_end);
+        let result = rule(self);
+        if result.is_none() {
+            self.pos = pos;
+            self.last_end = last_end;
+        }
+        result
+    }
+
+    fn advance(&mut self, len: usize) {
+        self.pos += len;
+        self.last_end = self.pos;
+        self.spacing();
+    }
+
+    fn spacing(&mut self) {
+        loop {
+            let rest = self.rest();
+            let skip = if rest.starts_with(|c| " \n\r\t".contains(c)) {
+                1
+            } else if rest.starts_with('⍝') {
+                rest.find('\n').unwrap_or(rest.len())
+            } else {
+                break
+            };
+            self.pos += skip;
+        }
+    }
+
+    fn token(&mut self, token: &str, name: &'static str) -> Option<()> {
+        if self.rest().starts_with(token) {
+            self.advance(token.len());
+            Some(())
+        } else {
+            self.expect(name);
+            None
+        }
+    }
+
+    fn program(&mut self) -> Option<(Vec<Import>, Expression)> {
+        self.spacing();
+        let start = self.pos;
+        let (mut imports, mut defs) = (vec![], vec![]);
+        loop {
+            if self.imports {
+                if let Some(i) = self.attempt(Parser::import) {
+                    imports.push(i);
+                    continue
+                }
+            }
+            match self.attempt(Parser::definition) {
+                Some(d) => defs.push(d),
+                None => break,
+            }
+        }
+        let e = self.expression();
+        if self.pos < self.code.len() {
+            self.expect("end of input");
+            return None
+        }
+        if defs.is_empty() {
+            Some((imports, e))
+        } else {
+            Some((imports, Expression::Definitions(defs, Box::new(e), self.span_from(start))))
+        }
+    }
+
+    fn import(&mut self) -> Option<Import> {
+        let start = self.pos;
+        let name = self.name()?;
+        self.token("⇐", "`⇐`")?;
+        let path = match self.string()? {
+            Expression::String(path, _) => path,
+            _ => unreachable!(),
         };
+        self.token("⋄", "`⋄`")?;
+        Some(Import { name, path, span: self.span_from(start) })
+    }
 
-        chain.fold(init, |e1, (w, e2)| {
-            Expression::Composition(vec![
-                Expression::Concatenation(vec![e1, e2]),
-                Expression::Word(w)
-            ])
+    fn definition(&mut self) -> Option<Definition<Expression>> {
+        let start = self.pos;
+        let name = self.name()?;
+        let declared = self.attempt(|p| {
+            let taken = p.count()?;
+            p.token("→", "`→`")?;
+            let left = p.count()?;
+            Some(Arity(taken, left))
+        });
+        self.token("←", "`←`")?;
+        let body = self.expression();
+        self.token("⋄", "`⋄`")?;
+        Some(Definition { name, declared, body, span: self.span_from(start) })
+    }
+
+    /// How many values a definition takes or leaves
+    fn count(&mut self) -> Option<u32> {
+        let len = self.digits(self.pos, 10);
+        let count = self.code[self.pos..self.pos + len].replace('_', "").parse::<u32>().ok();
+        match count {
+            None => self.expect("a count of values"),
+            Some(count) if count > MAX_COUNT => self.expect("a count of at most 255 values"),
+            Some(_) => {
+                self.advance(len);
+                return count
+            },
+        }
+        None
+    }
+
+    fn expression(&mut self) -> Expression {
+        let start = self.pos;
+        self.attempt(Parser::composition)
+            .or_else(|| self.attempt(Parser::concatenation))
+            .or_else(|| self.non_empty())
+            .or_else(|| self.plain())
+            .unwrap_or_else(|| Expression::Nop(Span::new(start, start, self.code)))
+    }
+
+    fn non_empty(&mut self) -> Option<Expression> {
+        self.infix()
+            .or_else(|| self.attempt(Parser::concatenation))
+            .or_else(|| self.attempt(Parser::ensquared))
+            .or_else(|| self.attempt(Parser::enclosed))
+    }
+
+    fn plain(&mut self) -> Option<Expression> {
+        self.attempt(Parser::string)
+            .or_else(|| self.number())
+            .or_else(|| self.word())
+            .or_else(|| self.call())
+    }
+
+    fn infix(&mut self) -> Option<Expression> {
+        let start = self.pos;
+        self.attempt(|p| p.infix_chain(start))
+            .or_else(|| self.attempt(|p| {
+                let e = p.simple()?;
+                let inf = p.infixed()?;
+                Some(Expression::InfixLeft(Box::new(e), Box::new(inf), p.span_from(start)))
+            }))
+            .or_else(|| self.attempt(|p| {
+                let inf = p.infixed()?;
+                let e = p.simple()?;
+                Some(Expression::InfixRight(Box::new(inf), Box::new(e), p.span_from(start)))
+            }))
+    }
+
+    fn infix_chain(&mut self, start: usize) -> Option<Expression> {
+        let mut e1 = self.simple()?;
+        let mut chained = false;
+        while let Some((w, e2)) = self.attempt(|p| {
+            let w = p.infixed()?;
+            let e2 = p.simple()?;
+            Some((w, e2))
+        }) {
+            let span = self.span_from(start);
+            e1 = Expression::Composition(vec![
+                Expression::Concatenation(vec![e1, e2], span),
+                w
+            ], span);
+            chained = true;
+        }
+        if chained { Some(e1) } else { None }
+    }
+
+    fn infixed(&mut self) -> Option<Expression> {
+        // “Grave accent”? Sorry, no burial here.
+        // Why don't you just call it “groove”?
+        self.token("`", "`` ` ``")?;
+        let w = self.word().or_else(|| self.call())?;
+        self.token("`", "`` ` ``")?;
+        Some(w)
+    }
+
+    fn concatenation(&mut self) -> Option<Expression> {
+        let start = self.pos;
+        let mut conc = vec![self.simple()?];
+        while let Some(e) = self.attempt(|p| { p.token(";", "`;`")?; p.simple() }) {
+            conc.push(e);
+        }
+        if conc.len() > 1 {
+            Some(Expression::Concatenation(conc, self.span_from(start)))
+        } else {
+            None
+        }
+    }
+
+    fn composition(&mut self) -> Option<Expression> {
+        let start = self.pos;
+        let head = self.infix()
+            .or_else(|| self.attempt(Parser::concatenation))
+            .or_else(|| self.simple())?;
+        let mut comp = vec![head];
+        while let Some(e) = self.non_empty().or_else(|| self.plain()) {
+            comp.push(e);
+        }
+        if comp.len() > 1 {
+            Some(Expression::Composition(comp, self.span_from(start)))
+        } else {
+            None
+        }
+    }
+
+    fn question(&mut self) -> Option<Expression> {
+        let start = self.pos;
+        self.token("?", "`?`")?;
+        let cons = self.expression();
+        self.token(":", "`:`")?;
+        let alter = self.expression();
+        self.token(".", "`.`")?;
+        Some(Expression::Question(Box::new(cons), Box::new(alter), self.span_from(start)))
+    }
+
+    fn simple(&mut self) -> Option<Expression> {
+        let start = self.pos;
+        if let Some(memo) = self.simples.get(&start).cloned() {
+            return memo.map(|(e, pos, last_end)| {
+                self.pos = pos;
+                self.last_end = last_end;
+                e
+            })
+        }
+
+        let e = self.attempt(Parser::question)
+            .or_else(|| self.attempt(Parser::ensquared))
+            .or_else(|| self.attempt(Parser::enclosed))
+            .or_else(|| self.attempt(Parser::string))
+            .or_else(|| self.number())
+            .or_else(|| self.word())
+            .or_else(|| self.call());
+        let memo = e.clone().map(|e| (e, self.pos, self.last_end));
+        self.simples.insert(start, memo);
+        e
+    }
+
+    fn enclosed(&mut self) -> Option<Expression> {
+        self.token("(", "`(`")?;
+        let e = self.expression();
+        self.token(")", "`)`")?;
+        Some(e)
+    }
+
+    fn ensquared(&mut self) -> Option<Expression> {
+        let start = self.pos;
+        self.token("[", "`[`")?;
+        let q = self.expression();
+        self.token("]", "`]`")?;
+        Some(Expression::Quotation(Box::new(q), self.span_from(start)))
+    }
+
+    fn word(&mut self) -> Option<Expression> {
+        let start = self.pos;
+        let w = WORDS.iter().cloned().find(|w| self.rest().starts_with(w.symbol()));
+        match w {
+            Some(w) => {
+                self.advance(w.symbol().len());
+                Some(Expression::Word(w, self.span_from(start)))
+            },
+            None => {
+                self.expect("a word");
+                None
+            },
+        }
+    }
+
+    fn name(&mut self) -> Option<String> {
+        let rest = self.rest();
+        if !rest.starts_with(char::is_alphabetic) {
+            self.expect("a name");
+            return None
+        }
+        let len = rest.find(|c: char| !is_name_char(c)).unwrap_or(rest.len());
+        self.advance(len);
+        Some(rest[..len].to_string())
+    }
+
+    /// A name, maybe one from an import, like `list::sum`
+    fn call(&mut self) -> Option<Expression> {
+        let start = self.pos;
+        let mut name = self.name()?;
+        while self.pos == self.last_end && self.rest().starts_with("::") {
+            let at = self.pos + "::".len();
+            if !self.code[at..].starts_with(char::is_alphabetic) { break }
+            self.pos = at;
+            name.push_str("::");
+            name.push_str(&self.name()?);
+        }
+        Some(Expression::Call(name, self.span_from(start)))
+    }
+
+    /// How many bytes of `radix` digits there are at `from`, with single underscores between them
+    fn digits(&self, from: usize, radix: u32) -> usize {
+        let mut len = 0;
+        let mut chars = self.code[from..].char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            if c.is_digit(radix) {
+                len = i + 1;
+            } else if c == '_' && len > 0 && len == i {
+                // Only between two digits
+                if !chars.peek().is_some_and(|&(_, c)| c.is_digit(radix)) { break }
+            } else {
+                break
+            }
+        }
+        len
+    }
+
+    fn number(&mut self) -> Option<Expression> {
+        let start = self.pos;
+        let from = if self.rest().starts_with('¯') { start + '¯'.len_utf8() } else { start };
+        let radix = RADICES.iter().find(|&&(prefix, _, _)| self.code[from..].starts_with(prefix));
+        let (number, end) = match radix {
+            Some(&(prefix, radix, name)) => self.radix(from + prefix.len(), radix, name)?,
+            None => self.decimal(from)?,
+        };
+
+        // `1e` is not `1 e`, and `0b12` is not `0b1 2`
+        if self.code[end..].starts_with(is_name_char) {
+            self.expect_at(end, "a digit");
+            return None
+        }
+
+        let negative = from > start;
+        self.advance(end - start);
+        let span = self.span_from(start);
+        Some(match number {
+            Number::Integer(i) => Expression::Integer(if negative { -i } else { i }, span),
+            Number::Rational(r) => Expression::Rational(if negative { -r } else { r }, span),
+            Number::Float(f) => Expression::Float(if negative { -f } else { f }, span),
         })
     }
 
-    fn infix_left(exp: Expression, inf: Word) -> Expression {
-        Expression::InfixLeft(
-            Box::new(exp),
-            Box::new(Expression::Word(inf)),
-        )
+    fn radix(&mut self, from: usize, radix: u32, name: &'static str) -> Option<(Number, usize)> {
+        let len = self.digits(from, radix);
+        if len == 0 {
+            self.expect_at(from, name);
+            return None
+        }
+        let digits = self.code[from..from + len].replace('_', "");
+        let int = BigInt::parse_bytes(digits.as_bytes(), radix).unwrap();
+        Some((Number::Integer(int), from + len))
+    }
+
+    fn decimal(&mut self, from: usize) -> Option<(Number, usize)> {
+        let int = self.digits(from, 10);
+        if int == 0 {
+            self.expect_at(from, "a number");
+            return None
+        }
+        let mut end = from + int;
+
+        if self.code[end..].starts_with('r') {
+            let len = self.digits(end + 1, 10);
+            if len == 0 {
+                self.expect_at(end + 1, "a denominator");
+            } else {
+                let numer = decimal_integer(&self.code[from..end]);
+                let denom = decimal_integer(&self.code[end + 1..end + 1 + len]);
+                if denom.is_zero() {
+                    self.expect_at(end + 1, "a nonzero denominator");
+                    return None
+                }
+                return Some((Number::Rational(BigRational::new(numer, denom)), end + 1 + len))
+            }
+        }
+
+        // Where the literal stopped being an integer
+        let mut float = None;
+        if self.code[end..].starts_with('.') {
+            let frac = self.digits(end + 1, 10);
+            if frac > 0 {
+                float = Some(end);
+                end += 1 + frac;
+            }
+        }
+        if self.code[end..].starts_with(['e', 'E']) {
+            let sign = if self.code[end + 1..].starts_with('¯') { '¯'.len_utf8() } else { 0 };
+            let exp = self.digits(end + 1 + sign, 10);
+            if exp == 0 {
+                self.expect_at(end + 1 + sign, "an exponent");
+            } else {
+                float = Some(end);
+                end += 1 + sign + exp;
+            }
+        }
+
+        match float {
+            None => Some((Number::Integer(decimal_integer(&self.code[from..end])), end)),
+            Some(at) => {
+                let float: f64 = self.code[from..end].replace('_', "").replace('¯', "-").parse().unwrap();
+                if float.is_infinite() {
+                    self.expect_at(at, "a float within range");
+                    return None
+                }
+                Some((Number::Float(float), end))
+            },
+        }
+    }
+
+    fn string(&mut self) -> Option<Expression> {
+        let start = self.pos;
+        self.token("'", "a string")?;
+        let mut string = String::new();
+        while let Some(c) = self.rest().chars().next() {
+            match c {
+                '\'' => break,
+                '\\' => string.push(self.escape()?),
+                c => {
+                    string.push(c);
+                    self.pos += c.len_utf8();
+                },
+            }
+        }
+        self.token("'", "`'`")?;
+        Some(Expression::String(string, self.span_from(start)))
     }
 
-    fn infix_right(inf: Word, exp: Expression) -> Expression {
-        Expression::InfixRight(
-            Box::new(Expression::Word(inf)),
-            Box::new(exp),
-        )
+    /// Decode the escape sequence at the backslash
+    fn escape(&mut self) -> Option<char> {
+        let at = self.pos + 1;
+        let c = match self.code[at..].chars().next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('0') => '\0',
+            Some('\'') => '\'',
+            Some('\\') => '\\',
+            Some('u') => return self.unicode_escape(at + 1),
+            _ => {
+                self.expect_at(at, "an escape sequence");
+                return None
+            },
+        };
+        self.pos = at + 1;
+        Some(c)
     }
 
-    fn composition(head: Expression, mut tail: Vec<Expression>) -> Expression {
-        tail.insert(0, head);
-        Expression::Composition(tail)
+    /// `\u{1F600}`, starting right after the `u`
+    fn unicode_escape(&mut self, from: usize) -> Option<char> {
+        if !self.code[from..].starts_with('{') {
+            self.expect_at(from, "`{`");
+            return None
+        }
+        let digits = from + 1;
+        let len = self.code[digits..].find(|c: char| !c.is_ascii_hexdigit()).unwrap_or(self.code.len() - digits);
+        if len == 0 {
+            self.expect_at(digits, "a hexadecimal digit");
+            return None
+        }
+        if !self.code[digits + len..].starts_with('}') {
+            self.expect_at(digits + len, "`}`");
+            return None
+        }
+        let c = u32::from_str_radix(&self.code[digits..digits + len], 16).ok().and_then(::std::char::from_u32);
+        if c.is_none() {
+            self.expect_at(digits, "a Unicode code point");
+        }
+        self.pos = digits + len + 1;
+        c
     }
+}
+
+/// Why the source is not a program
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    /// The furthest point the parser managed to reach
+    pub span: Span,
+    /// Tokens that would have been accepted there
+    pub expected: Vec<String>,
+    /// The offending line, underlined
+    pub excerpt: String,
+}
 
-    fn concatenation(head: Expression, mut tail: Vec<Expression>) -> Expression {
-        tail.insert(0, head);
-        Expression::Concatenation(tail)
+impl ParseError {
+    fn new(offset: usize, expected: Vec<String>, code: &str) -> Self {
+        let end = code[offset..].chars().next().map_or(offset, |c| offset + c.len_utf8());
+        let span = Span::new(offset, end, code);
+        let mut expected = expected;
+        expected.sort();
+        expected.dedup();
+        ParseError {
+            span,
+            expected,
+            excerpt: span.excerpt(code),
+        }
     }
 
-    fn question(cons: Expression, alter: Expression) -> Expression {
-        Expression::Question(Box::new(cons), Box::new(alter))
+    /// What the parser was in the middle of, judging by what it wanted to see next
+    fn context(&self) -> Option<&'static str> {
+        let wants = |t: &str| self.expected.iter().any(|e| e == t);
+        if wants("`:`") {
+            Some("to continue `?` question")
+        } else if wants("`.`") {
+            Some("to close `?` question")
+        } else if wants("`)`") {
+            Some("to close `(`")
+        } else if wants("`]`") {
+            Some("to close `[`")
+        } else if wants("`'`") {
+            Some("to close string")
+        } else if wants("an escape sequence") || wants("`}`") {
+            Some("in string")
+        } else if wants("`⋄`") {
+            Some("to end definition")
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.expected.len() {
+            0 => write!(f, "unexpected input")?,
+            1 => write!(f, "expected {}", self.expected[0])?,
+            _ => write!(f, "expected one of {}", self.expected.join(", "))?,
+        }
+        if let Some(context) = self.context() {
+            write!(f, " {}", context)?;
+        }
+        writeln!(f, " at {}:{}", self.span.line, self.span.column)?;
+        write!(f, "{}", self.excerpt)
     }
 }
 
-pub fn parse(code: String) -> Result<Expression, ()> {
-    obsc::parse_program(code.into_state()).data.ok_or(())
+/// Parse code that has no file to import anything relative to
+pub fn parse(code: &str) -> Result<Expression, ParseError> {
+    run(Parser::new(code)).map(|(_, expr)| expr)
+}
+
+/// Parse the contents of a file, which may start with imports
+pub fn parse_module(code: &str) -> Result<(Vec<Import>, Expression), ParseError> {
+    let mut parser = Parser::new(code);
+    parser.imports = true;
+    run(parser)
+}
+
+fn run(mut parser: Parser) -> Result<(Vec<Import>, Expression), ParseError> {
+    match parser.program() {
+        Some(program) => Ok(program),
+        None => {
+            let expected = parser.expected.iter().map(|e| e.to_string()).collect();
+            Err(ParseError::new(parser.furthest, expected, parser.code))
+        },
+    }
 }
 
 // I'm not a TDD programmer. But when I make my code compiling, I really doubt whether my code even works
@@ -237,38 +701,174 @@ mod tests {
     // You don't have to put magical files in voodoo places or decide between hunit vs hspec vs tasty vs etc
     // You just make test functions in the module of tests. Simple as that.
 
-    use oak_runtime::*;
+    use num_rational::BigRational;
 
-    use super::obsc;
+    use super::{parse, parse_module};
     use ast::Expression::*;
     use ast::Word::*;
+    use ast::tests::unspanned;
+    use ast::{Arity, Definition, Expression, Span};
+
+    fn parsed(code: &str) -> Option<Expression> {
+        parse(code).ok().map(unspanned)
+    }
 
     #[test] fn simple_postfix() {
-        let ast = obsc::parse_program("2 2 + 3 −".into_state());
+        let ast = parsed("2 2 + 3 −");
+        let s = Span::default();
         assert_eq!(
-            Some(Composition(vec![Integer(2), Integer(2), Word(Plus), Integer(3), Word(Minus)])),
-            ast.data
+            Some(Composition(vec![Integer(2.into(), s), Integer(2.into(), s), Word(Plus, s), Integer(3.into(), s), Word(Minus, s)], s)),
+            ast
         );
     }
 
     #[test] fn simple_concat() {
-        let ast = obsc::parse_program("2 2 3 3 ×;× +".into_state());
+        let ast = parsed("2 2 3 3 ×;× +");
+        let s = Span::default();
         assert_eq!(
             Some(Composition(vec![
-                Integer(2), Integer(2), Integer(3), Integer(3),
-                Concatenation(vec![Word(Prod), Word(Prod)]),
-                Word(Plus)
-            ])),
-            ast.data
+                Integer(2.into(), s), Integer(2.into(), s), Integer(3.into(), s), Integer(3.into(), s),
+                Concatenation(vec![Word(Prod, s), Word(Prod, s)], s),
+                Word(Plus, s)
+            ], s)),
+            ast
+        );
+    }
+
+    #[test] fn numbers() {
+        let s = Span::default();
+        assert_eq!(
+            parsed("123456789012345678901234567890"),
+            Some(Integer("123456789012345678901234567890".parse().unwrap(), s))
+        );
+        assert_eq!(
+            parsed("6r4"),
+            Some(Rational(BigRational::new(3.into(), 2.into()), s))
         );
+        assert_eq!(parsed("1.5"), Some(Float(1.5, s)));
+        assert!(parse("1r0").unwrap_err().expected.contains(&"a nonzero denominator".to_string()));
+    }
+
+    #[test] fn fancy_numbers() {
+        let s = Span::default();
+        assert_eq!(parsed("¯3"), Some(Integer((-3).into(), s)));
+        assert_eq!(parsed("¯1.5"), Some(Float(-1.5, s)));
+        assert_eq!(parsed("¯3r4"), Some(Rational(BigRational::new((-3).into(), 4.into()), s)));
+        assert_eq!(parsed("1e9"), Some(Float(1e9, s)));
+        assert_eq!(parsed("2.5E¯3"), Some(Float(2.5e-3, s)));
+        assert_eq!(parsed("0xFF"), Some(Integer(255.into(), s)));
+        assert_eq!(parsed("¯0b1_01"), Some(Integer((-5).into(), s)));
+        assert_eq!(parsed("0o17"), Some(Integer(15.into(), s)));
+        assert_eq!(parsed("1_000_000"), Some(Integer(1_000_000.into(), s)));
+        assert_eq!(parse("¯3 4").unwrap().span().end, "¯3 4".len());
+    }
+
+    #[test] fn bad_numbers() {
+        let error = parse("1 1e999 +").unwrap_err();
+        assert_eq!(error.expected, vec!["a float within range"]);
+        assert_eq!(error.span.column, 4);
+        assert_eq!(parse("0x ⎕").unwrap_err().expected, vec!["a hexadecimal digit"]);
+        assert_eq!(parse("0b2").unwrap_err().span.column, 3);
+        assert_eq!(parse("1e+3").unwrap_err().expected, vec!["an exponent"]);
+        assert!(parse("1__000").is_err());
+        assert!(parse("1_").is_err());
+        assert!(parse("¯").is_err());
+    }
+
+    #[test] fn strings() {
+        let s = Span::default();
+        assert_eq!(parsed("'\\na\\tb'"), Some(String("\na\tb".to_string(), s)));
+        assert_eq!(parsed("'it\\'s \\\\n'"), Some(String("it's \\n".to_string(), s)));
+        assert_eq!(parsed("'\\u{48}\\u{1F600}'"), Some(String("H😀".to_string(), s)));
+        assert_eq!(parsed("'no ⍝ comment'"), Some(String("no ⍝ comment".to_string(), s)));
+    }
+
+    #[test] fn bad_escapes() {
+        let error = parse("1 'a\\qb' ⎕").unwrap_err();
+        assert_eq!(error.expected, vec!["an escape sequence"]);
+        assert_eq!(error.span.column, 6);
+        assert_eq!(error.to_string().lines().next(), Some("expected an escape sequence in string at 1:6"));
+        assert_eq!(parse("'\\u{110000}'").unwrap_err().expected, vec!["a Unicode code point"]);
+        assert_eq!(parse("'\\u{}'").unwrap_err().expected, vec!["a hexadecimal digit"]);
+        assert_eq!(parse("'\\u{41'").unwrap_err().expected, vec!["`}`"]);
+        assert_eq!(parse("'\\u41'").unwrap_err().expected, vec!["`{`"]);
+        assert_eq!(parse("'\\").unwrap_err().expected, vec!["an escape sequence"]);
     }
 
     #[test] fn simple_infix() {
-        let ast = obsc::parse_program("⍬`,`·`,`·`,`·".into_state());
-        let should_be = obsc::parse_program("((⍬;· ,);· ,);· ,".into_state());
+        let ast = parsed("⍬`,`·`,`·`,`·");
+        let should_be = parsed("((⍬;· ,);· ,);· ,");
+        assert_eq!(
+            ast,
+            should_be
+        );
+    }
+
+    #[test] fn definitions() {
+        let s = Span::default();
+        let square = Definition {
+            name: "square".to_string(),
+            declared: None,
+            body: Composition(vec![Word(Dup, s), Word(Prod, s)], s),
+            span: s,
+        };
         assert_eq!(
-            ast.data,
-            should_be.data
+            parsed("square ← ⇈ × ⋄ 3 square"),
+            Some(Definitions(vec![square], Box::new(Composition(vec![Integer(3.into(), s), Call("square".to_string(), s)], s)), s))
         );
+        match parse("f 1 → 2 ← ⇈ ⋄ g_2 ← f ⋄ 1 g_2") {
+            Ok(Definitions(ref defs, _, _)) => {
+                assert_eq!(defs[0].declared, Some(Arity(1, 2)));
+                assert_eq!((defs[1].name.as_str(), defs[1].declared), ("g_2", None));
+            },
+            e => panic!("Not definitions: {:?}", e),
+        }
+        assert_eq!(parse("f 1 → 4000000000 ← 1 ⋄ 1").unwrap_err().expected, vec!["a count of at most 255 values"]);
+        assert!(parse("f 4000000000 → 0 ← ↓ ⋄ f;f").is_err());
+        // Names are not numbers
+        assert_eq!(parsed("x1"), Some(Call("x1".to_string(), s)));
+        assert_eq!(parse("2x").unwrap_err().expected, vec!["a digit"]);
+
+        // Imports only come from files, and names from imports have their prefixes
+        assert!(parse("list ⇐ 'list.eso' ⋄ 1").is_err());
+        match parse_module("list ⇐ 'list.eso' ⋄ list::sum;list") {
+            Ok((ref imports, Concatenation(ref conc, _))) => {
+                assert_eq!((imports[0].name.as_str(), imports[0].path.as_str()), ("list", "list.eso"));
+                assert_eq!(unspanned(conc[0].clone()), Call("list::sum".to_string(), s));
+                assert_eq!(unspanned(conc[1].clone()), Call("list".to_string(), s));
+            },
+            p => panic!("Not an import: {:?}", p),
+        }
+        assert!(parse("a::b ← 1 ⋄ 1").is_err());
+        assert!(parse("a ::b").is_err());
+
+        let error = parse("square ← ⇈ × 3 square").unwrap_err();
+        assert!(error.expected.contains(&"`⋄`".to_string()));
+        assert!(error.to_string().contains("to end definition"));
+    }
+
+    #[test] fn spans() {
+        let ast = parse("1 [2 +]\n'x' ⎕").unwrap();
+        match ast {
+            Composition(ref v, _) => {
+                let s = v[1].span();
+                assert_eq!((s.start, s.line, s.column), (2, 1, 3));
+                let s = v[2].span();
+                assert_eq!((s.start, s.line, s.column), (8, 2, 1));
+            },
+            _ => panic!("Not a composition: {:?}", ast),
+        }
+    }
+
+    #[test] fn unfinished_question() {
+        let error = parse("1 2 =\n(?'yes' 'no'.⎕)").unwrap_err();
+        assert!(error.expected.contains(&"`:`".to_string()));
+        assert_eq!((error.span.line, error.span.column), (2, 13));
+        assert_eq!(error.excerpt, "  |\n2 | (?'yes' 'no'.⎕)\n  |             ^");
+    }
+
+    #[test] fn stray_character() {
+        let error = parse("1 2 +\n3 $ ⎕").unwrap_err();
+        assert_eq!((error.span.line, error.span.column), (2, 3));
     }
 }
@@ -0,0 +1,74 @@
+use super::ast::{Arited, Definition, Span};
+use super::parser;
+
+/// Words every program gets, written in esobsc itself
+pub const SOURCE: &str = include_str!("prelude.eso");
+
+/// The words of the prelude, as if imported by code whose beginning is `span`
+///
+/// The words that code defines itself as well are hidden from it, under names no one can write,
+/// so that the words of the prelude that call them still get the prelude's own.
+pub fn definitions(span: Span, shadowed: &[String]) -> Vec<Definition<Arited>> {
+    let expr = parser::parse(SOURCE).expect("IIE: The prelude does not parse");
+    let hide = |name: String| if shadowed.contains(&name) { format!("prelude:{}", name) } else { name };
+    match Arited::from_expression(expr).expect("IIE: The prelude does not check") {
+        Arited::Definitions(defs, _, _) => defs.into_iter().map(|d| d.imported(None, span).renamed(&hide)).collect(),
+        _ => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::eval::{Data, Machine};
+    use super::super::module;
+    use super::super::types::{self, Type};
+    use super::super::Error;
+
+    /// What is left on the stack, bottom first
+    fn stack(code: &str) -> Result<Vec<String>, Error> {
+        let program = module::load(code, None, true)?;
+        let mut machine = Machine::new();
+        machine.execute_program(&program)?;
+        Ok(machine.stack().iter().map(Data::to_string).collect())
+    }
+
+    /// What `word` does, as the type of a quotation of it
+    fn effect(word: &str) -> String {
+        let quoted = module::load(&format!("[{}]", word), None, true).unwrap();
+        match types::check(&quoted).unwrap().output.types.pop() {
+            Some(Type::Generic(e)) => e.to_string(),
+            t => panic!("Not a quotation: {:?}", t),
+        }
+    }
+
+    #[test] fn shuffling() {
+        assert_eq!(stack("1 2 over").unwrap(), vec!["1", "2", "1"]);
+        assert_eq!(stack("1 2 3 rot").unwrap(), vec!["2", "3", "1"]);
+        assert_eq!(stack("1 2 nip").unwrap(), vec!["2"]);
+        assert_eq!(stack("1 2 tuck").unwrap(), vec!["2", "1", "2"]);
+        assert_eq!(effect("rot"), "R… a b c → R… b c a");
+    }
+
+    #[test] fn comparing() {
+        assert_eq!(stack("3 7 max 7 3 max").unwrap(), vec!["7", "7"]);
+        assert_eq!(stack("3 7 min 7 3 min").unwrap(), vec!["3", "3"]);
+        assert_eq!(stack("'b' 'a' max").unwrap(), vec!["'b'"]);
+    }
+
+    #[test] fn lists() {
+        assert_eq!(stack("⍬1,2,3, length ⍬ length").unwrap(), vec!["3", "0"]);
+        assert_eq!(stack("⍬1,2,3, reverse ⍬ reverse").unwrap(), vec!["⍬3,2,1,", "⍬"]);
+        assert_eq!(stack("⍬1,2,3,4, sum ⍬ sum").unwrap(), vec!["10", "0"]);
+        assert_eq!(stack("⍬1,2,3,4, product ⍬ product").unwrap(), vec!["24", "1"]);
+        assert_eq!(effect("length"), "R… List a → R… Number");
+        assert_eq!(effect("reverse"), "R… List a → R… List a");
+    }
+
+    #[test] fn programs_come_first() {
+        assert_eq!(stack("sum ← + ⋄ 1 2 sum").unwrap(), vec!["3"]);
+        // But only in the program: the prelude keeps its own
+        assert_eq!(stack("over ← ↓ ⋄ 1 2 3 tuck 4 5 over").unwrap(), vec!["1", "3", "2", "3", "4"]);
+        assert!(matches!(stack("'a' length"), Err(Error::Type(_))));
+        assert!(module::load("1 2 over", None, false).is_err());
+    }
+}
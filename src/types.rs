@@ -0,0 +1,697 @@
+use super::ast::{Arited, Arity, Span, Word};
+
+use std::collections::HashMap;
+use std::fmt;
+
+// Types are stack effects all the way down. A word takes a stack and leaves a stack,
+// and all it cares about is the top of it. What is below is a row variable: `R…` in
+//
+//     ⇈ : R… a → R… a a
+//
+// Numbers are a single type here, since they turn into each other as they need to.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+    Var(usize),
+    Number,
+    String,
+    Bool,
+    List(Box<Type>),
+    Quotation(Box<Effect>),
+    /// A quotation as it was written, which gets new variables every time it is used
+    Generic(Box<Effect>),
+}
+
+/// Some types on top of a row of whatever, the topmost type last
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stack {
+    pub row: usize,
+    pub types: Vec<Type>,
+}
+
+/// What a piece of code takes from the stack and what it leaves there
+#[derive(Debug, Clone, PartialEq)]
+pub struct Effect {
+    pub input: Stack,
+    pub output: Stack,
+}
+
+/// A value of one type where another one was expected
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypeError {
+    /// The code that wanted `expected`
+    pub span: Span,
+    pub expected: String,
+    pub found: String,
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "expected {}, found {} at {}:{}",
+            self.expected, self.found, self.span.line, self.span.column
+        )
+    }
+}
+
+/// Two types that could not be made one, already written down
+struct Clash {
+    expected: String,
+    found: String,
+}
+
+/// Variables of one type paired with the variables of another, one to one
+#[derive(Default)]
+struct Renaming {
+    types: Vec<(usize, usize)>,
+    rows: Vec<(usize, usize)>,
+}
+
+impl Renaming {
+    fn pair(pairs: &mut Vec<(usize, usize)>, a: usize, b: usize) -> bool {
+        match pairs.iter().find(|&&(x, y)| x == a || y == b) {
+            Some(&(x, y)) => x == a && y == b,
+            None => {
+                pairs.push((a, b));
+                true
+            },
+        }
+    }
+
+    fn types(&mut self, a: &Type, b: &Type) -> bool {
+        match (a, b) {
+            (Type::Var(a), Type::Var(b)) => Renaming::pair(&mut self.types, *a, *b),
+            (Type::List(a), Type::List(b)) => self.types(a, b),
+            (Type::Quotation(a), Type::Quotation(b)) | (Type::Generic(a), Type::Generic(b)) => self.effects(a, b),
+            (a, b) => a == b,
+        }
+    }
+
+    fn stacks(&mut self, a: &Stack, b: &Stack) -> bool {
+        a.types.len() == b.types.len()
+            && Renaming::pair(&mut self.rows, a.row, b.row)
+            && a.types.iter().zip(&b.types).all(|(a, b)| self.types(a, b))
+    }
+
+    fn effects(&mut self, a: &Effect, b: &Effect) -> bool {
+        self.stacks(&a.input, &b.input) && self.stacks(&a.output, &b.output)
+    }
+}
+
+/// Names for variables, handed out in the order they show up
+#[derive(Default)]
+struct Names {
+    types: Vec<usize>,
+    rows: Vec<usize>,
+}
+
+impl Names {
+    fn type_var(&mut self, v: usize) -> String {
+        let i = self.types.iter().position(|&t| t == v).unwrap_or_else(|| {
+            self.types.push(v);
+            self.types.len() - 1
+        });
+        match "abcdefghijklmnopqrstuvwxyz".chars().nth(i) {
+            Some(c) => c.to_string(),
+            None => format!("t{}", i),
+        }
+    }
+
+    fn row_var(&mut self, v: usize) -> String {
+        let i = self.rows.iter().position(|&r| r == v).unwrap_or_else(|| {
+            self.rows.push(v);
+            self.rows.len() - 1
+        });
+        match "RSTUVW".chars().nth(i) {
+            Some(c) => format!("{}…", c),
+            None => format!("R{}…", i),
+        }
+    }
+
+    fn show_type(&mut self, t: &Type) -> String {
+        match *t {
+            Type::Var(v) => self.type_var(v),
+            Type::Number => "Number".to_string(),
+            Type::String => "String".to_string(),
+            Type::Bool => "Bool".to_string(),
+            Type::List(ref t) => match **t {
+                Type::List(_) => format!("List ({})", self.show_type(t)),
+                _ => format!("List {}", self.show_type(t)),
+            },
+            Type::Quotation(ref e) | Type::Generic(ref e) => format!("[{}]", self.show_effect(e)),
+        }
+    }
+
+    fn show_stack(&mut self, s: &Stack) -> String {
+        let mut shown = self.row_var(s.row);
+        for t in &s.types {
+            shown.push(' ');
+            shown.push_str(&self.show_type(t));
+        }
+        shown
+    }
+
+    fn show_effect(&mut self, e: &Effect) -> String {
+        let input = self.show_stack(&e.input);
+        format!("{} → {}", input, self.show_stack(&e.output))
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", Names::default().show_type(self))
+    }
+}
+
+impl fmt::Display for Effect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", Names::default().show_effect(self))
+    }
+}
+
+/// What is known about a defined word
+#[derive(Clone)]
+enum Defined {
+    /// Its body is still being looked into: calls get the same types, but may be made on any stack
+    Pending(Effect),
+    /// Calls get new variables, like quotation literals do
+    Done(Effect),
+}
+
+/// Type variables and row variables, and what they turned out to be
+#[derive(Default)]
+struct Checker {
+    types: Vec<Option<Type>>,
+    rows: Vec<Option<Stack>>,
+    /// Rows that stand for a stack no one may look into, so they are never bound to anything with types on it
+    rigid: Vec<usize>,
+    /// Type variables for values that get compared, which anything but a quotation can be
+    ordered: Vec<usize>,
+    words: HashMap<String, Defined>,
+}
+
+impl Checker {
+    fn fresh(&mut self) -> Type {
+        self.types.push(None);
+        Type::Var(self.types.len() - 1)
+    }
+
+    fn fresh_row(&mut self) -> usize {
+        self.rows.push(None);
+        self.rows.len() - 1
+    }
+
+    fn fresh_ordered(&mut self, ordered: bool) -> Type {
+        let t = self.fresh();
+        if let (true, Type::Var(v)) = (ordered, &t) {
+            self.ordered.push(*v);
+        }
+        t
+    }
+
+    fn rigid_row(&mut self) -> usize {
+        let row = self.fresh_row();
+        self.rigid.push(row);
+        row
+    }
+
+    fn fresh_types(&mut self, n: u32) -> Vec<Type> {
+        (0..n).map(|_| self.fresh()).collect()
+    }
+
+    /// `t` with its variable followed, if it is a bound one
+    fn shallow(&self, t: &Type) -> Type {
+        match *t {
+            Type::Var(v) => match self.types[v] {
+                Some(ref t) => self.shallow(t),
+                None => t.clone(),
+            },
+            _ => t.clone(),
+        }
+    }
+
+    /// `s` with its row followed down to an unbound one
+    fn stack(&self, s: &Stack) -> Stack {
+        let mut s = s.clone();
+        while let Some(below) = self.rows[s.row].clone() {
+            let mut types = below.types;
+            types.extend(s.types);
+            s = Stack { row: below.row, types };
+        }
+        s
+    }
+
+    /// `t` with every bound variable in it replaced, all the way down
+    fn deep(&self, t: &Type) -> Type {
+        match self.shallow(t) {
+            Type::List(t) => Type::List(Box::new(self.deep(&t))),
+            Type::Quotation(e) => Type::Quotation(Box::new(self.deep_effect(&e))),
+            Type::Generic(e) => Type::Generic(Box::new(self.deep_effect(&e))),
+            t => t,
+        }
+    }
+
+    fn deep_stack(&self, s: &Stack) -> Stack {
+        let s = self.stack(s);
+        Stack { row: s.row, types: s.types.iter().map(|t| self.deep(t)).collect() }
+    }
+
+    fn deep_effect(&self, e: &Effect) -> Effect {
+        Effect { input: self.deep_stack(&e.input), output: self.deep_stack(&e.output) }
+    }
+
+    /// A copy of `e` with all of its variables new
+    ///
+    /// Quotation literals see nothing outside of themselves, so this is all it takes to use one
+    /// on stacks of different types.
+    fn instantiate(&mut self, e: &Effect) -> Effect {
+        let e = self.deep_effect(e);
+        let (mut types, mut rows) = (HashMap::new(), HashMap::new());
+        self.rename_effect(&e, &mut types, &mut rows)
+    }
+
+    fn rename(&mut self, t: &Type, types: &mut HashMap<usize, Type>, rows: &mut HashMap<usize, usize>) -> Type {
+        match *t {
+            Type::Var(v) => {
+                let ordered = self.ordered.contains(&v);
+                types.entry(v).or_insert_with(|| self.fresh_ordered(ordered)).clone()
+            },
+            Type::List(ref t) => Type::List(Box::new(self.rename(t, types, rows))),
+            Type::Quotation(ref e) => Type::Quotation(Box::new(self.rename_effect(e, types, rows))),
+            ref t => t.clone(),
+        }
+    }
+
+    fn rename_stack(&mut self, s: &Stack, types: &mut HashMap<usize, Type>, rows: &mut HashMap<usize, usize>) -> Stack {
+        let rigid = self.rigid.contains(&s.row);
+        let row = *rows.entry(s.row).or_insert_with(|| if rigid { self.rigid_row() } else { self.fresh_row() });
+        Stack { row, types: s.types.iter().map(|t| self.rename(t, types, rows)).collect() }
+    }
+
+    fn rename_effect(&mut self, e: &Effect, types: &mut HashMap<usize, Type>, rows: &mut HashMap<usize, usize>) -> Effect {
+        let input = self.rename_stack(&e.input, types, rows);
+        Effect { input, output: self.rename_stack(&e.output, types, rows) }
+    }
+
+    /// `e` on a stack of its own, with the same types on top
+    fn rerow(&mut self, e: &Effect) -> Effect {
+        let e = self.deep_effect(e);
+        let input = self.fresh_row();
+        let output = if e.output.row == e.input.row { input } else { self.fresh_row() };
+        Effect {
+            input: Stack { row: input, types: e.input.types },
+            output: Stack { row: output, types: e.output.types },
+        }
+    }
+
+    /// Anything at all, as long as it is `arity`
+    fn unknown(&mut self, Arity(taken, left): Arity) -> Effect {
+        let row = self.fresh_row();
+        Effect {
+            input: Stack { row, types: self.fresh_types(taken) },
+            output: Stack { row, types: self.fresh_types(left) },
+        }
+    }
+
+    fn occurs(&self, v: usize, t: &Type) -> bool {
+        match self.deep(t) {
+            Type::Var(u) => u == v,
+            Type::List(t) => self.occurs(v, &t),
+            Type::Quotation(e) => {
+                e.input.types.iter().chain(&e.output.types).any(|t| self.occurs(v, t))
+            },
+            _ => false,
+        }
+    }
+
+    fn row_occurs(&self, row: usize, types: &[Type]) -> bool {
+        types.iter().any(|t| match self.deep(t) {
+            Type::List(t) => self.row_occurs(row, &[*t]),
+            Type::Quotation(e) => {
+                e.input.row == row || e.output.row == row
+                    || self.row_occurs(row, &e.input.types) || self.row_occurs(row, &e.output.types)
+            },
+            _ => false,
+        })
+    }
+
+    fn clash(&self, expected: &Type, found: &Type) -> Clash {
+        let mut names = Names::default();
+        let expected = match self.deep(expected) {
+            Type::Var(v) if self.ordered.contains(&v) => "a value that can be ordered".to_string(),
+            t => names.show_type(&t),
+        };
+        Clash { expected, found: names.show_type(&self.deep(found)) }
+    }
+
+    fn stack_clash(&self, expected: &Stack, found: &Stack) -> Clash {
+        let mut names = Names::default();
+        Clash {
+            expected: names.show_stack(&self.deep_stack(expected)),
+            found: names.show_stack(&self.deep_stack(found)),
+        }
+    }
+
+    fn unify(&mut self, expected: &Type, found: &Type) -> Result<(), Clash> {
+        let clash = self.clash(expected, found);
+        self.unify_types(expected, found).map_err(|_| clash)
+    }
+
+    /// Fails without telling why: the caller knows better what to blame
+    fn unify_types(&mut self, expected: &Type, found: &Type) -> Result<(), ()> {
+        match (self.shallow(expected), self.shallow(found)) {
+            (Type::Var(a), Type::Var(b)) if a == b => Ok(()),
+            (Type::Var(v), t) | (t, Type::Var(v)) => {
+                if self.occurs(v, &t) { return Err(()) }
+                if self.ordered.contains(&v) { self.order(&t)? }
+                self.types[v] = Some(t);
+                Ok(())
+            },
+            // Two quotations that may be either one, like the elements of a list: they have to be
+            // usable in the same ways, so nothing but their variables may differ
+            (Type::Generic(a), Type::Generic(b)) => {
+                let (a, b) = (self.deep_effect(&a), self.deep_effect(&b));
+                if Renaming::default().effects(&a, &b) { Ok(()) } else { Err(()) }
+            },
+            (Type::Generic(a), b) => {
+                let a = Type::Quotation(Box::new(self.instantiate(&a)));
+                self.unify_types(&a, &b)
+            },
+            (a, Type::Generic(b)) => {
+                let b = Type::Quotation(Box::new(self.instantiate(&b)));
+                self.unify_types(&a, &b)
+            },
+            (Type::List(a), Type::List(b)) => self.unify_types(&a, &b),
+            (Type::Quotation(a), Type::Quotation(b)) => {
+                self.unify_rows(&a.input, &b.input)?;
+                self.unify_rows(&a.output, &b.output)
+            },
+            (a, b) => if a == b { Ok(()) } else { Err(()) },
+        }
+    }
+
+    /// Make sure `t` can be compared, now and whatever its variables turn out to be
+    fn order(&mut self, t: &Type) -> Result<(), ()> {
+        match self.shallow(t) {
+            Type::Var(v) => {
+                if !self.ordered.contains(&v) { self.ordered.push(v) }
+                Ok(())
+            },
+            Type::List(t) => self.order(&t),
+            Type::Quotation(_) | Type::Generic(_) => Err(()),
+            _ => Ok(()),
+        }
+    }
+
+    fn unify_stacks(&mut self, expected: &Stack, found: &Stack) -> Result<(), Clash> {
+        let (e, f) = (self.stack(expected), self.stack(found));
+        let common = e.types.len().min(f.types.len());
+        let (e_below, e_top) = e.types.split_at(e.types.len() - common);
+        let (f_below, f_top) = f.types.split_at(f.types.len() - common);
+        // From the bottom up, so that it is the later value that gets blamed
+        for (e, f) in e_top.iter().zip(f_top) {
+            self.unify(e, f)?;
+        }
+        let (e_rest, f_rest) = (
+            Stack { row: e.row, types: e_below.to_vec() },
+            Stack { row: f.row, types: f_below.to_vec() },
+        );
+        let clash = self.stack_clash(&e_rest, &f_rest);
+        self.unify_rows(&e_rest, &f_rest).map_err(|_| clash)
+    }
+
+    /// Stacks that have already been followed down to their rows
+    fn unify_rows(&mut self, expected: &Stack, found: &Stack) -> Result<(), ()> {
+        let (e, f) = (self.stack(expected), self.stack(found));
+        if e.types.len() != f.types.len() && !e.types.is_empty() && !f.types.is_empty() {
+            let common = e.types.len().min(f.types.len());
+            let (e_below, e_top) = e.types.split_at(e.types.len() - common);
+            let (f_below, f_top) = f.types.split_at(f.types.len() - common);
+            for (e, f) in e_top.iter().zip(f_top) {
+                self.unify_types(e, f)?;
+            }
+            return self.unify_rows(
+                &Stack { row: e.row, types: e_below.to_vec() },
+                &Stack { row: f.row, types: f_below.to_vec() },
+            )
+        }
+        if e.types.len() == f.types.len() {
+            for (e, t) in e.types.iter().zip(&f.types) {
+                self.unify_types(e, t)?;
+            }
+            if e.row != f.row {
+                let (row, other) = if self.rigid.contains(&e.row) { (f.row, e.row) } else { (e.row, f.row) };
+                if self.rigid.contains(&row) { return Err(()) }
+                self.rows[row] = Some(Stack { row: other, types: vec![] });
+            }
+            return Ok(())
+        }
+        // One of them is a bare row, which is whatever the other one is
+        let (row, stack) = if e.types.is_empty() { (e.row, f) } else { (f.row, e) };
+        if stack.row == row || self.rigid.contains(&row) || self.row_occurs(row, &stack.types) { return Err(()) }
+        self.rows[row] = Some(stack);
+        Ok(())
+    }
+
+    /// A new copy of what `w` does to the stack
+    fn signature(&mut self, w: Word) -> Effect {
+        use self::Word::*;
+
+        let row = self.fresh_row();
+        let (a, b) = (self.fresh_ordered(matches!(w, Gt | Lt)), self.fresh());
+        let list = Type::List(Box::new(a.clone()));
+        let rest = |types: Vec<Type>| Stack { row, types };
+        let quotation = |input: Stack, output: Stack| Type::Quotation(Box::new(Effect { input, output }));
+
+        let (input, output) = match w {
+            Gt | Lt => (vec![a.clone(), a], vec![Type::Bool]),
+            Eq | NotEq => (vec![a, b], vec![Type::Bool]),
+            True | False => (vec![], vec![Type::Bool]),
+            And | Or | Xor => (vec![Type::Bool, Type::Bool], vec![Type::Bool]),
+            Not => (vec![Type::Bool], vec![Type::Bool]),
+            Plus | Minus | Prod | Div | Rem | Pow => (vec![Type::Number, Type::Number], vec![Type::Number]),
+            Floor | Ceil | Round | Trunc | ToFloat | Negate | Abs => (vec![Type::Number], vec![Type::Number]),
+            Swap => (vec![a.clone(), b.clone()], vec![b, a]),
+            Dup => (vec![a.clone()], vec![a.clone(), a]),
+            Drop => (vec![a], vec![]),
+            Id => (vec![a.clone()], vec![a]),
+            Zilde => (vec![], vec![list]),
+            Comma => (vec![list.clone(), a], vec![list]),
+            Behead | Curtail => (vec![list.clone()], vec![a, list]),
+            Length => (vec![list], vec![Type::Number]),
+            Index => (vec![list, Type::Number], vec![a]),
+            Catenate => (vec![list.clone(), list.clone()], vec![list]),
+            Reverse => (vec![list.clone()], vec![list]),
+            Take | Skip => (vec![list.clone(), Type::Number], vec![list]),
+            Member => (vec![list, a], vec![Type::Bool]),
+            Print => (vec![a], vec![]),
+            Show => (vec![a], vec![Type::String]),
+            ReadLine => (vec![], vec![Type::String, Type::Bool]),
+            ReadAll => (vec![], vec![Type::String]),
+            ReadNumber => (vec![], vec![Type::Number, Type::Bool]),
+            Rec => (vec![quotation(rest(vec![]), rest(vec![Type::Bool]))], vec![]),
+            Apply => {
+                let after = self.fresh_row();
+                return Effect {
+                    input: rest(vec![quotation(rest(vec![]), Stack { row: after, types: vec![] })]),
+                    output: Stack { row: after, types: vec![] },
+                }
+            },
+            Times => (vec![quotation(rest(vec![]), rest(vec![])), Type::Number], vec![]),
+            While => (
+                vec![quotation(rest(vec![]), rest(vec![Type::Bool])), quotation(rest(vec![]), rest(vec![]))],
+                vec![],
+            ),
+        };
+        Effect { input: rest(input), output: rest(output) }
+    }
+
+    fn infer(&mut self, expr: &Arited) -> Result<Effect, TypeError> {
+        use self::Arited::*;
+
+        let blame = |span: Span| move |c: Clash| TypeError { span, expected: c.expected, found: c.found };
+        let pushes = |checker: &mut Checker, t: Type| {
+            let row = checker.fresh_row();
+            Effect { input: Stack { row, types: vec![] }, output: Stack { row, types: vec![t] } }
+        };
+
+        match *expr {
+            Composition(ref comp, _, _) => {
+                let row = self.fresh_row();
+                let mut effect = Effect { input: Stack { row, types: vec![] }, output: Stack { row, types: vec![] } };
+                for e in comp {
+                    let next = self.infer(e)?;
+                    self.unify_stacks(&next.input, &effect.output).map_err(blame(e.span()))?;
+                    effect.output = next.output;
+                }
+                Ok(effect)
+            },
+            // Every part gets its own values, and cannot see below them: what is below is rigid
+            Concatenation(ref conc, _, _) => {
+                let row = self.fresh_row();
+                let mut effect = Effect { input: Stack { row, types: vec![] }, output: Stack { row, types: vec![] } };
+                for e in conc {
+                    let part = self.infer(e)?;
+                    let arity = e.arity();
+                    let below = self.rigid_row();
+                    let input = Stack { row: below, types: self.fresh_types(arity.0) };
+                    let output = Stack { row: below, types: self.fresh_types(arity.1) };
+                    self.unify_stacks(&part.input, &input).map_err(blame(e.span()))?;
+                    self.unify_stacks(&part.output, &output).map_err(blame(e.span()))?;
+                    effect.input.types.extend(input.types);
+                    effect.output.types.extend(output.types);
+                }
+                Ok(effect)
+            },
+            Question(ref cons, ref alter, _, _) => {
+                let c = self.infer(cons)?;
+                let a = self.infer(alter)?;
+                self.unify_stacks(&c.input, &a.input).map_err(blame(alter.span()))?;
+                self.unify_stacks(&c.output, &a.output).map_err(blame(alter.span()))?;
+                let mut input = self.stack(&c.input);
+                input.types.push(Type::Bool);
+                Ok(Effect { input, output: c.output })
+            },
+            Word(w, _, _) => Ok(self.signature(w)),
+            Integer(..) | Rational(..) | Float(..) => Ok(pushes(self, Type::Number)),
+            String(..) => Ok(pushes(self, Type::String)),
+            Quotation(ref q, _) => {
+                let body = self.infer(q)?;
+                Ok(pushes(self, Type::Generic(Box::new(body))))
+            },
+            IdN(n, _) => {
+                let stack = Stack { row: self.fresh_row(), types: self.fresh_types(n) };
+                Ok(Effect { input: stack.clone(), output: stack })
+            },
+            Call(ref name, arity, _) => Ok(match self.words.get(name).cloned() {
+                Some(Defined::Done(e)) => self.instantiate(&e),
+                Some(Defined::Pending(e)) => self.rerow(&e),
+                None => self.unknown(arity),
+            }),
+            Definitions(ref defs, ref main, _) => {
+                for d in defs {
+                    if let Some(declared) = d.declared {
+                        let effect = self.unknown(declared);
+                        self.words.insert(d.name.clone(), Defined::Pending(effect));
+                    }
+                }
+                for d in defs {
+                    let body = self.infer(&d.body)?;
+                    if let Some(Defined::Pending(declared)) = self.words.get(&d.name).cloned() {
+                        self.unify_stacks(&declared.input, &body.input).map_err(blame(d.span))?;
+                        self.unify_stacks(&declared.output, &body.output).map_err(blame(d.span))?;
+                    }
+                    let effect = self.deep_effect(&body);
+                    self.words.insert(d.name.clone(), Defined::Done(effect));
+                }
+                self.infer(main)
+            },
+        }
+    }
+}
+
+/// Infer what a program does to the stack, or find where its types do not fit
+///
+/// Every use of a quotation is checked on its own, so one that is run twice may be run on stacks
+/// of different types. Quotations that end up in one list or come out of either branch of a
+/// question have to be of the same type, up to the names of their variables.
+pub fn check(program: &Arited) -> Result<Effect, TypeError> {
+    let mut checker = Checker::default();
+    let effect = checker.infer(program)?;
+    Ok(checker.deep_effect(&effect))
+}
+
+/// Like `check`, for a whole program, which has nothing on the stack to start with
+pub fn check_program(program: &Arited) -> Result<Effect, TypeError> {
+    let effect = check(program)?;
+    if !effect.input.types.is_empty() {
+        return Err(TypeError {
+            span: program.span(),
+            expected: "nothing on the stack".to_string(),
+            found: Names::default().show_stack(&effect.input),
+        })
+    }
+    Ok(effect)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::parser;
+    use super::super::ast::Arited;
+    use super::{check, check_program, Effect, TypeError};
+
+    fn infer(code: &str) -> Result<Effect, TypeError> {
+        check(&Arited::from_expression(parser::parse(code).unwrap()).unwrap())
+    }
+
+    fn effect(code: &str) -> String {
+        infer(code).unwrap().to_string()
+    }
+
+    fn error(code: &str) -> (String, String, usize) {
+        let e = infer(code).unwrap_err();
+        (e.expected, e.found, e.span.column)
+    }
+
+    #[test] fn effects() {
+        assert_eq!(effect("1 2 +"), "R… → R… Number");
+        assert_eq!(effect("⇈"), "R… a → R… a a");
+        assert_eq!(effect("↔ ↓"), "R… a b → R… b");
+        assert_eq!(effect("⍬ 1 , 'a' ⎕"), "R… → R… List Number");
+        assert_eq!(effect("⍬ ⍬ 1 , ,"), "R… → R… List (List Number)");
+        assert_eq!(effect("(?1:2.·) ⍕"), "R… Bool → R… String");
+        assert_eq!(effect("+;(1 −)"), "R… Number Number Number → R… Number Number");
+        assert_eq!(effect("[1 +]"), "R… → R… [S… Number → S… Number]");
+        assert_eq!(effect("[+] ⍎"), "R… Number Number → R… Number");
+        assert_eq!(effect("[1] ⇈ ⍎ ↔ ⍎"), "R… → R… Number Number");
+        assert_eq!(effect("0 10 [⇈ +;(1 −) ⇈ 0 >] ∇"), "R… → R… Number Number");
+        assert_eq!(effect("[⇈ 100 <] [2 ×] ⍤"), "R… Number → R… Number");
+        assert_eq!(effect("⍞ ↓ ⍈ ↓ ⍇ ="), "R… → R… String Bool");
+        assert_eq!(effect("⍘"), "R… List a → R… a List a");
+        assert_eq!(effect("⍬1, ⍬2, ⍪ 0 ⌷"), "R… → R… Number");
+        assert_eq!(effect("⌽ 1 ↑ 'a' ∊"), "R… List String → R… Bool");
+        // Defined words are as polymorphic as quotations
+        assert_eq!(effect("two ← ⇈ ⋄ 1 two 'a' two"), "R… → R… Number Number String String");
+        assert_eq!(effect("fact 1 → 1 ← ⇈ 1 > (?⇈ 1 − fact ×:↓ 1. ·) ⋄ fact"), "R… Number → R… Number");
+    }
+
+    #[test] fn mismatches() {
+        assert_eq!(error("7 1 'one' +"), ("Number".into(), "String".into(), 11));
+        assert_eq!(error("1 2 ,"), ("List a".into(), "Number".into(), 5));
+        assert_eq!(error("⍬1,'a',"), ("Number".into(), "String".into(), 7));
+        assert_eq!(error("1 (?2:3.·)"), ("Bool".into(), "Number".into(), 4));
+        assert_eq!(error("1 2 = (?'a':3. ⎕)"), ("String".into(), "Number".into(), 13));
+        assert_eq!(error("'a' 1 <"), ("String".into(), "Number".into(), 7));
+        assert_eq!(error("[1 +] [1 +] <"), ("a value that can be ordered".into(), "[R… Number → R… Number]".into(), 13));
+        assert!(infer("⍬[1], ⍬[2], >").is_err());
+        assert!(infer("lt ← < ⋄ [1] ⇈ lt").is_err());
+        assert!(infer("⍬1, ⍬2, < ⍬'a', ⍬'b', >").is_ok());
+        assert_eq!(error("⍬1, ⍬'a', ⍪"), ("List Number".into(), "List String".into(), 11));
+        assert_eq!(error("⊤ 1 [2 +] ⍎ ∧"), ("Bool".into(), "Number".into(), 13));
+        // Quotations are looked into wherever they go
+        assert_eq!(error("[1 +] 'a' ↔ ⍎"), ("R… Number".into(), "S… String".into(), 13));
+        assert_eq!(error("0 ·;[⇈ 1 +] ∇").0, "[R… → R… Bool]");
+        assert_eq!(error("0 ⍬ [⇈] , ['s'] , 1 ⌷ 5 ↔ ⍎ + ⎕"), ("[R… a → R… a a]".into(), "[S… → S… String]".into(), 17));
+        assert_eq!(error("⊤ (?[1]:[⇈]. ·) ⍎").2, 9);
+        // Parts of a concatenation cannot reach below the values they are given
+        assert_eq!(error("3 4 [1 +] ·;⍎"), ("[R… → R…]".into(), "[S… Number → S… Number]".into(), 11));
+        assert_eq!(effect("7 0 10 (1 +);([⇈ +;(1 −) ⇈ 0 >] ∇ ↓)"), "R… → R… Number Number");
+        assert_eq!(error("0 10 ⍬[⇈ +;(1 −) ⇈ 0 >], 0 ⌷ (∇ ↓);1").0, "[R… Number → R… Number Bool]");
+        assert_eq!(error("sq ← ⇈ × ⋄ 'a' sq"), ("Number".into(), "String".into(), 16));
+        assert_eq!(error("f 1 → 1 ← ⇈ 1 < (?↓ 'a':1 − f 1 +. ·) ⋄ 1 f"), ("String".into(), "Number".into(), 25));
+    }
+
+    #[test] fn programs_take_nothing() {
+        let program = |code: &str| check_program(&Arited::from_expression(parser::parse(code).unwrap()).unwrap());
+        assert!(program("1 2 + ⎕").is_ok());
+        let e = program("1 ·;[+] ⍎ ⎕").unwrap_err();
+        assert_eq!((e.expected.as_str(), e.found.as_str()), ("nothing on the stack", "R… Number"));
+        assert_eq!(program("1 [↓] ⍎ ⍎").unwrap_err().found, "R… [R… → S…]");
+    }
+
+    #[test] fn no_infinite_types() {
+        assert!(infer("[⇈ ⍎] ⇈ ⍎").is_err());
+        assert!(infer("⍬ ⇈ ,").is_err());
+    }
+}